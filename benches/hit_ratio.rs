@@ -0,0 +1,71 @@
+// Hit ratios of every eviction policy on the synthetic traces the policy
+// tests assert on, printed as a table. The traces are seeded generators
+// shaped like a few access patterns, not recordings of real traffic, so
+// the numbers compare policies with each other rather than predict
+// production hit ratios.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use raw_cache::{Cache, EvictionPolicy, Expiry, FifoPolicy, LfuPolicy, LruPolicy, RandomPolicy, TinyLfuPolicy};
+
+const CAPACITY: usize = 1_000;
+
+// Half the requests go to a hot set that fits in the cache; the other half
+// sweep through a key range far larger than it.
+fn scan_trace() -> Vec<u64> {
+    let mut rng = StdRng::seed_from_u64(8);
+    let mut next_scan_key = 1_000_000;
+    let mut trace = Vec::new();
+    for _ in 0..100 {
+        for _ in 0..1_000 {
+            trace.push(rng.gen_range(0..CAPACITY as u64 / 2));
+        }
+        for _ in 0..1_000 {
+            trace.push(next_scan_key);
+            next_scan_key += 1;
+        }
+    }
+    trace
+}
+
+// A loop over slightly more keys than fit.
+fn loop_trace() -> Vec<u64> {
+    (0..100_000).map(|i| i % (CAPACITY as u64 * 5 / 4)).collect()
+}
+
+// Popularity falling off steeply with rank, over a hundred times more keys
+// than fit.
+fn skewed_trace() -> Vec<u64> {
+    let mut rng = StdRng::seed_from_u64(8);
+    let keys = CAPACITY as f64 * 100.0;
+    (0..100_000)
+        .map(|_| keys.powf(rng.gen::<f64>()) as u64)
+        .collect()
+}
+
+// Replays `trace` as a read-through cache would and returns the hit ratio.
+fn hit_ratio<P: EvictionPolicy<u64>>(trace: &[u64], policy: P) -> f64 {
+    let mut cache = Cache::with_policy(CAPACITY, policy);
+    for &key in trace {
+        if cache.get(&key).is_none() {
+            cache.set(key, (), Expiry::Never).unwrap();
+        }
+    }
+    cache.stats().hit_ratio()
+}
+
+fn main() {
+    let traces = [("scan", scan_trace()), ("loop", loop_trace()), ("skewed", skewed_trace())];
+    println!("{:<8} {:>7} {:>7} {:>7} {:>7} {:>7}", "trace", "lru", "lfu", "fifo", "random", "tinylfu");
+    for (name, trace) in &traces {
+        println!(
+            "{:<8} {:>7.3} {:>7.3} {:>7.3} {:>7.3} {:>7.3}",
+            name,
+            hit_ratio(trace, LruPolicy::new(CAPACITY)),
+            hit_ratio(trace, LfuPolicy::new(CAPACITY)),
+            hit_ratio(trace, FifoPolicy::new(CAPACITY)),
+            hit_ratio(trace, RandomPolicy::with_rng(CAPACITY, StdRng::seed_from_u64(8))),
+            hit_ratio(trace, TinyLfuPolicy::new(CAPACITY)),
+        );
+    }
+}
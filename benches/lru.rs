@@ -0,0 +1,154 @@
+// Recency bookkeeping should cost the same per operation whether the cache
+// holds a thousand entries or a million. `LruPolicy` is a thin wrapper over
+// the crate's slab-indexed list, so it stands in for it here.
+//
+// Expect the million-entry runs to be a few times slower than the small ones
+// from CPU cache misses alone; a linear scan would make them a thousand times
+// slower.
+
+use std::time::{Duration, Instant};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use raw_cache::{Cache, EvictionPolicy, Expiry, LruPolicy};
+
+const SIZES: [u64; 2] = [1_000, 1_000_000];
+
+// Steps through every key of a cache of `size` entries in a scattered order;
+// the multiplier is a prime that divides neither size.
+fn scattered(i: u64, size: u64) -> u64 {
+    (i % size) * 999_983 % size
+}
+
+fn filled_policy(size: u64) -> LruPolicy<u64> {
+    let mut policy = LruPolicy::new(size as usize);
+    for key in 0..size {
+        policy.on_insert(&key);
+    }
+    policy
+}
+
+fn filled_cache(size: u64) -> Cache<u64, u64> {
+    let mut cache = Cache::new(size as usize);
+    for key in 0..size {
+        cache.set(key, key, Expiry::Never).unwrap();
+    }
+    cache
+}
+
+// Times `op` on `iters` distinct keys of `state`, calling `restore` untimed
+// for each of them after every pass over the whole key range so each timed
+// call finds its key present.
+fn timed_passes<S>(
+    iters: u64,
+    size: u64,
+    state: &mut S,
+    op: impl Fn(&mut S, u64),
+    restore: impl Fn(&mut S, u64),
+) -> Duration {
+    let mut elapsed = Duration::ZERO;
+    let mut remaining = iters;
+    while remaining > 0 {
+        let pass = remaining.min(size);
+        let start = Instant::now();
+        for i in 0..pass {
+            op(state, scattered(i, size));
+        }
+        elapsed += start.elapsed();
+        for i in 0..pass {
+            restore(state, scattered(i, size));
+        }
+        remaining -= pass;
+    }
+    elapsed
+}
+
+fn lru_list(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lru_list");
+    for size in SIZES {
+        let mut policy = filled_policy(size);
+        let mut i = 0;
+        group.bench_with_input(BenchmarkId::new("touch", size), &size, |b, &size| {
+            b.iter(|| {
+                i += 1;
+                policy.on_access(&scattered(i, size));
+            })
+        });
+
+        let mut next = size;
+        group.bench_with_input(BenchmarkId::new("push_back", size), &size, |b, _| {
+            b.iter(|| {
+                next += 1;
+                policy.on_insert(&next);
+                let victim = policy.victim().unwrap();
+                policy.on_remove(&victim);
+            })
+        });
+
+        let mut policy = filled_policy(size);
+        group.bench_with_input(BenchmarkId::new("remove", size), &size, |b, &size| {
+            b.iter_custom(|iters| {
+                timed_passes(
+                    iters,
+                    size,
+                    &mut policy,
+                    |policy, key| policy.on_remove(&key),
+                    |policy, key| policy.on_insert(&key),
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+fn cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache");
+    for size in SIZES {
+        let mut cache = filled_cache(size);
+        let mut i = 0;
+        group.bench_with_input(BenchmarkId::new("get", size), &size, |b, &size| {
+            b.iter(|| {
+                i += 1;
+                black_box(cache.get(&scattered(i, size)));
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("overwrite", size), &size, |b, &size| {
+            b.iter(|| {
+                i += 1;
+                let key = scattered(i, size);
+                cache.set(key, i, Expiry::Never).unwrap();
+            })
+        });
+
+        // The cache is full, so every new key evicts the least recently
+        // used one.
+        let mut next = size;
+        group.bench_with_input(BenchmarkId::new("set_evicting", size), &size, |b, _| {
+            b.iter(|| {
+                next += 1;
+                cache.set(next, next, Expiry::Never).unwrap();
+            })
+        });
+
+        let mut cache = filled_cache(size);
+        group.bench_with_input(BenchmarkId::new("delete", size), &size, |b, &size| {
+            b.iter_custom(|iters| {
+                timed_passes(
+                    iters,
+                    size,
+                    &mut cache,
+                    |cache, key| {
+                        black_box(cache.delete(&key));
+                    },
+                    |cache, key| {
+                        cache.set(key, key, Expiry::Never).unwrap();
+                    },
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lru_list, cache);
+criterion_main!(benches);
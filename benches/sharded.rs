@@ -0,0 +1,72 @@
+// Throughput of a read-mostly workload as the number of concurrent tasks
+// grows. With one shard every operation contends on the same lock; with
+// enough shards throughput should grow close to linearly with the tasks, up
+// to the number of cores.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use raw_cache::{Cache, Expiry, ShardedCache};
+use tokio::runtime::Runtime;
+
+const KEYS: u64 = 100_000;
+const TASKS: [u64; 4] = [1, 2, 4, 8];
+const SHARDS: [usize; 2] = [1, 64];
+
+fn filled(runtime: &Runtime, shards: usize) -> Arc<ShardedCache<u64, u64>> {
+    let cache = Arc::new(ShardedCache::new(shards, KEYS as usize, Cache::new));
+    runtime.block_on(async {
+        for key in 0..KEYS {
+            cache.set(key, key, Expiry::Never).await.unwrap();
+        }
+    });
+    cache
+}
+
+// Runs `ops` operations on each of `tasks` tasks at once, one write for every
+// nine reads, and returns how long it took them all.
+fn run(runtime: &Runtime, cache: &Arc<ShardedCache<u64, u64>>, tasks: u64, ops: u64) -> Duration {
+    runtime.block_on(async {
+        let start = Instant::now();
+        let handles: Vec<_> = (0..tasks)
+            .map(|task| {
+                let cache = Arc::clone(cache);
+                tokio::spawn(async move {
+                    for i in 0..ops {
+                        let key = (task * ops + i) * 999_983 % KEYS;
+                        if i % 10 == 0 {
+                            cache.set(key, i, Expiry::Never).await.unwrap();
+                        } else {
+                            cache.get(&key).await.unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        start.elapsed()
+    })
+}
+
+fn scaling(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(*TASKS.last().unwrap() as usize)
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("sharded");
+    for shards in SHARDS {
+        let cache = filled(&runtime, shards);
+        for tasks in TASKS {
+            group.throughput(Throughput::Elements(tasks));
+            let id = BenchmarkId::new(format!("{}_shards", shards), format!("{}_tasks", tasks));
+            group.bench_function(id, |b| b.iter_custom(|ops| run(&runtime, &cache, tasks, ops)));
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scaling);
+criterion_main!(benches);
@@ -0,0 +1,1229 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::expiry::{Expiry, Refresh, Refreshing, Schedule, Sliding};
+use crate::listener::{RemovalCause, RemovalListener};
+use crate::stats::CacheStats;
+use crate::policy::{EvictionPolicy, LruPolicy};
+use crate::weigher::{ByteLen, EntryCount, Weigher};
+
+struct CacheEntry<V> {
+    value: V,
+    expiration: Option<Instant>,
+    // Taken from a cache-wide counter on every write, so it identifies one
+    // particular value of the key. Also disambiguates entries sharing a
+    // deadline in the expiry index.
+    version: u64,
+    weight: usize,
+    sliding: Option<Sliding>,
+    refresh: Option<Refreshing>,
+}
+
+impl<V> CacheEntry<V> {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expiration.is_some_and(|expiration| expiration <= now)
+    }
+
+    // When the entry is dropped for good: its deadline plus any window in
+    // which it may still be served stale. Keys the expiry index.
+    fn dead_at(&self) -> Option<Instant> {
+        let grace = self.refresh.map_or(Duration::ZERO, Refreshing::grace);
+        self.expiration.and_then(|expiration| expiration.checked_add(grace))
+    }
+
+    fn is_dead(&self, now: Instant) -> bool {
+        self.dead_at().is_some_and(|dead_at| dead_at <= now)
+    }
+}
+
+// What a read for `ShardedCache::get_with_refresh` found.
+pub(crate) enum Lookup<V> {
+    Miss,
+    Fresh(V),
+    // Live, but past its refresh-ahead point.
+    RefreshDue(V),
+    // Expired, but may be served while a background reload runs.
+    StaleWhileRevalidate(V),
+    // Expired, but may be served if reloading it fails.
+    StaleIfError(V),
+}
+
+/// Values that can hold a signed 64-bit counter for [`Cache::incr_by`].
+pub trait Counter {
+    /// The counter held by this value, if it is one.
+    fn to_counter(&self) -> Option<i64>;
+    /// A value holding `value`.
+    fn from_counter(value: i64) -> Self;
+}
+
+impl Counter for String {
+    fn to_counter(&self) -> Option<i64> {
+        self.parse().ok()
+    }
+
+    fn from_counter(value: i64) -> Self {
+        value.to_string()
+    }
+}
+
+/// When [`Cache::set_if`] may write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetCondition {
+    /// Write unconditionally.
+    Always,
+    /// Write only if the key has no live entry.
+    IfAbsent,
+    /// Write only if the key has a live entry.
+    IfPresent,
+    /// Write only if the live entry has this version.
+    IfVersion(u64),
+}
+
+/// The result of a successful conditional write.
+#[derive(Debug)]
+pub struct SetOutcome<V> {
+    /// The value that was replaced, if the key had a live entry.
+    pub previous: Option<V>,
+    /// The version assigned to the new value.
+    pub version: u64,
+}
+
+/// Why a cache operation did not go through.
+#[derive(Debug, Clone)]
+pub enum CacheError {
+    /// The entry alone weighs more than the cache's weight budget.
+    TooLarge {
+        /// What the entry weighs.
+        weight: usize,
+        /// The cache's weight budget.
+        max_weight: usize,
+    },
+    /// The cache was created with a `max_size` of zero, so it cannot store
+    /// anything.
+    ZeroCapacity,
+    /// A [`SetCondition`] did not hold.
+    ConditionFailed {
+        /// The version of the live entry, if there is one.
+        current: Option<u64>,
+    },
+    /// A counter operation found a value that is not an integer.
+    NotAnInteger,
+    /// A counter operation would overflow `i64`.
+    Overflow,
+    /// The operation panicked; the affected shard was reset.
+    Panicked,
+    /// The loader passed to [`ShardedCache::get_with`](crate::ShardedCache::get_with)
+    /// failed. Shared by every caller that waited on the same load.
+    Load(Arc<dyn Error + Send + Sync>),
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheError::TooLarge { weight, max_weight } => write!(
+                f,
+                "entry weighs {} but the cache holds at most {}",
+                weight, max_weight
+            ),
+            CacheError::ZeroCapacity => write!(f, "the cache cannot hold any entries"),
+            CacheError::ConditionFailed { current: None } => write!(f, "key does not exist"),
+            CacheError::ConditionFailed {
+                current: Some(version),
+            } => write!(f, "key exists with version {}", version),
+            CacheError::NotAnInteger => write!(f, "value is not an integer"),
+            CacheError::Overflow => write!(f, "increment would overflow"),
+            CacheError::Panicked => write!(f, "cache operation failed unexpectedly"),
+            CacheError::Load(error) => write!(f, "loading the value failed: {}", error),
+        }
+    }
+}
+
+impl Error for CacheError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CacheError::Load(error) => Some(&**error),
+            _ => None,
+        }
+    }
+}
+
+/// A bounded key-value cache with per-entry expiry.
+///
+/// The cache holds at most `max_size` entries and, optionally, at most a
+/// total weight as measured by a [`Weigher`]. When a write would exceed
+/// either bound, the [`EvictionPolicy`] `P` picks entries to drop. Expired
+/// entries are invisible to reads and are reclaimed lazily or by
+/// [`Cache::purge_expired`].
+///
+/// Every write gets a fresh version number from a cache-wide counter, which
+/// [`Cache::compare_and_swap`] and [`SetCondition::IfVersion`] check against.
+/// Entries leaving the cache for any reason can be observed with
+/// [`Cache::with_removal_listener`].
+///
+/// ```
+/// use raw_cache::{Cache, Expiry};
+/// use std::time::Duration;
+///
+/// let mut cache = Cache::new(100);
+/// cache.set("greeting", "hello", Expiry::After(Duration::from_secs(60))).unwrap();
+/// assert_eq!(cache.get(&"greeting"), Some(&"hello"));
+/// ```
+pub struct Cache<K, V, P = LruPolicy<K>> {
+    data: HashMap<K, CacheEntry<V>>,
+    policy: P,
+    // Entries with a deadline, soonest first, so expired ones can be reclaimed
+    // without scanning `data`.
+    expirations: BTreeMap<(Instant, u64), K>,
+    next_version: u64,
+    max_size: usize,
+    weigher: Box<dyn Weigher<K, V> + Send + Sync>,
+    max_weight: Option<usize>,
+    weight: usize,
+    listener: Option<Arc<dyn RemovalListener<K, V>>>,
+    clock: Arc<dyn Clock>,
+    // Counters only; `entries` and `weight` are filled in by `stats()`.
+    stats: CacheStats,
+}
+
+impl<K, V> Cache<K, V>
+    where
+        K: Eq + Hash + Clone,
+{
+    /// Creates an LRU cache holding at most `max_size` entries.
+    pub fn new(max_size: usize) -> Self {
+        Cache::with_policy(max_size, LruPolicy::new(max_size))
+    }
+}
+
+impl<K, V, P> Cache<K, V, P>
+    where
+        K: Eq + Hash + Clone,
+        P: EvictionPolicy<K>,
+{
+    /// Creates a cache holding at most `max_size` entries, evicting with
+    /// `policy`.
+    pub fn with_policy(max_size: usize, policy: P) -> Self {
+        Cache {
+            data: HashMap::new(),
+            policy,
+            expirations: BTreeMap::new(),
+            next_version: 1,
+            max_size,
+            weigher: Box::new(EntryCount),
+            listener: None,
+            clock: Arc::new(SystemClock),
+            stats: CacheStats::default(),
+            max_weight: None,
+            weight: 0,
+        }
+    }
+
+    /// Bounds the cache by the total weight of its entries in addition to
+    /// `max_size`. Must be called before any entries are inserted.
+    pub fn with_weigher<W>(mut self, max_weight: usize, weigher: W) -> Self
+        where
+            W: Weigher<K, V> + Send + Sync + 'static,
+    {
+        self.weigher = Box::new(weigher);
+        self.max_weight = Some(max_weight);
+        self
+    }
+
+    /// Reports every entry that leaves the cache to `listener`. Pass the
+    /// same listener to each shard's cache to observe a whole
+    /// [`ShardedCache`](crate::ShardedCache).
+    ///
+    /// ```
+    /// use raw_cache::{Cache, Expiry, RemovalCause};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let removed = Arc::new(Mutex::new(Vec::new()));
+    /// let log = Arc::clone(&removed);
+    /// let mut cache = Cache::new(1).with_removal_listener(Arc::new(move |key: &&'static str, _: &u32, cause| {
+    ///     log.lock().unwrap().push((*key, cause));
+    /// }));
+    /// cache.set("a", 1, Expiry::Never).unwrap();
+    /// cache.set("a", 2, Expiry::Never).unwrap();
+    /// cache.set("b", 3, Expiry::Never).unwrap();
+    /// cache.delete(&"b");
+    ///
+    /// assert_eq!(
+    ///     *removed.lock().unwrap(),
+    ///     [("a", RemovalCause::Replaced), ("a", RemovalCause::Evicted(1)), ("b", RemovalCause::Explicit)],
+    /// );
+    /// ```
+    pub fn with_removal_listener(mut self, listener: Arc<dyn RemovalListener<K, V>>) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Reads the current time from `clock` rather than the system clock.
+    /// Every deadline the cache keeps, including those passed to
+    /// [`expire_at`](Cache::expire_at), is on this clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Bounds the cache by the byte length of its keys and values, as
+    /// [`with_weigher`](Cache::with_weigher) with [`ByteLen`].
+    pub fn with_max_weight(self, max_weight: usize) -> Self
+        where
+            K: AsRef<[u8]>,
+            V: AsRef<[u8]>,
+    {
+        self.with_weigher(max_weight, ByteLen)
+    }
+
+    // Lets a `ShardedCache` shard hold up to the entry limit of the whole
+    // sharded cache, which it enforces itself.
+    pub(crate) fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub(crate) fn max_weight(&self) -> Option<usize> {
+        self.max_weight
+    }
+
+    /// The number of stored entries, including expired ones not yet
+    /// reclaimed.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Whether no entries are stored.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The total weight of the stored entries.
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    /// A snapshot of the cache's statistics.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.data.len(),
+            weight: self.weight,
+            ..self.stats
+        }
+    }
+
+    /// Zeroes the statistics' counters and returns their values from just
+    /// before.
+    pub fn reset_stats(&mut self) -> CacheStats {
+        let stats = self.stats();
+        self.stats = CacheStats::default();
+        stats
+    }
+
+    /// Returns the live value for `key`, counting the read as a use and
+    /// extending a sliding deadline. An expired entry is removed, unless it
+    /// may still be served stale by
+    /// [`ShardedCache::get_with_refresh`](crate::ShardedCache::get_with_refresh).
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        if self.data.contains_key(key) {
+            let now = self.clock.now();
+            let entry = self.data.get(key).unwrap();
+
+            if entry.is_expired(now) {
+                if entry.is_dead(now) {
+                    self.remove_entry(key, RemovalCause::Expired);
+                }
+                self.policy.on_miss(key);
+                self.stats.misses += 1;
+                self.stats.expired_on_read += 1;
+                return None;
+            } else {
+                self.policy.on_access(key);
+                self.slide(key, now);
+                self.stats.hits += 1;
+            }
+        } else {
+            self.policy.on_miss(key);
+            self.stats.misses += 1;
+        }
+        self.data.get(key).map(|entry| &entry.value)
+    }
+
+    /// Like [`get`](Cache::get), but leaves recency, sliding deadlines and
+    /// expired entries alone, so it only needs `&self`.
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        let entry = self.data.get(key)?;
+        if entry.is_expired(self.clock.now()) {
+            return None;
+        }
+        Some(&entry.value)
+    }
+
+    // A live value that is not yet due for a refresh-ahead reload.
+    pub(crate) fn peek_fresh(&self, key: &K) -> Option<&V> {
+        let entry = self.data.get(key)?;
+        let now = self.clock.now();
+        let due = entry
+            .refresh
+            .and_then(|refresh| refresh.refresh_at)
+            .is_some_and(|refresh_at| refresh_at <= now);
+        if entry.is_expired(now) || due {
+            return None;
+        }
+        Some(&entry.value)
+    }
+
+    // Like `get`, but also reports entries that are due for a refresh or
+    // may be served stale.
+    pub(crate) fn lookup(&mut self, key: &K) -> Lookup<V>
+        where
+            V: Clone,
+    {
+        let now = self.clock.now();
+        let entry = match self.data.get(key) {
+            Some(entry) if !entry.is_dead(now) => entry,
+            _ => {
+                self.get(key);
+                return Lookup::Miss;
+            }
+        };
+        if !entry.is_expired(now) {
+            let value = self.get(key).cloned();
+            return match (value, self.peek_fresh(key)) {
+                (Some(value), Some(_)) => Lookup::Fresh(value),
+                (Some(value), None) => Lookup::RefreshDue(value),
+                (None, _) => Lookup::Miss,
+            };
+        }
+
+        self.policy.on_miss(key);
+        self.stats.misses += 1;
+        self.stats.expired_on_read += 1;
+        let stale_for = now.saturating_duration_since(entry.expiration.unwrap());
+        match entry.refresh {
+            Some(refresh) if stale_for < refresh.stale_while_revalidate => {
+                Lookup::StaleWhileRevalidate(entry.value.clone())
+            }
+            Some(refresh) if stale_for < refresh.stale_if_error => Lookup::StaleIfError(entry.value.clone()),
+            _ => Lookup::Miss,
+        }
+    }
+
+    /// Whether `key` has a live entry, without counting it as a use.
+    pub fn contains(&self, key: &K) -> bool {
+        self.peek(key).is_some()
+    }
+
+    /// The remaining time to live of `key`. Outer `None` means the key is
+    /// missing or expired, inner `None` means the entry never expires.
+    pub fn ttl(&self, key: &K) -> Option<Option<Duration>> {
+        let entry = self.data.get(key)?;
+        let now = self.clock.now();
+        if entry.is_expired(now) {
+            return None;
+        }
+        Some(entry.expiration.map(|expiration| expiration - now))
+    }
+
+    /// Gives a live entry a new expiry without rewriting its value. Returns
+    /// false if the key is missing or expired.
+    ///
+    /// An entry loaded with a [`Refresh`] keeps it, measured against the new
+    /// deadline.
+    pub fn expire(&mut self, key: &K, expiry: Expiry) -> bool {
+        self.set_schedule(key, expiry.schedule(self.clock.now()))
+    }
+
+    /// Like [`expire`](Cache::expire), with an absolute deadline.
+    pub fn expire_at(&mut self, key: &K, deadline: Instant) -> bool {
+        let schedule = Schedule {
+            deadline: Some(deadline),
+            sliding: None,
+            refresh: None,
+        };
+        self.set_schedule(key, schedule)
+    }
+
+    /// Removes the expiry of a live entry. Returns false if the key is
+    /// missing or expired.
+    pub fn persist(&mut self, key: &K) -> bool {
+        let schedule = Schedule {
+            deadline: None,
+            sliding: None,
+            refresh: None,
+        };
+        self.set_schedule(key, schedule)
+    }
+
+    /// Marks a live entry as used, as a read would, and optionally gives it a
+    /// new expiry.
+    pub fn touch(&mut self, key: &K, expiry: Option<Expiry>) -> bool {
+        if self.ttl(key).is_none() {
+            return false;
+        }
+        self.policy.on_access(key);
+        match expiry {
+            Some(expiry) => self.expire(key, expiry),
+            None => {
+                self.slide(key, self.clock.now());
+                true
+            }
+        }
+    }
+
+    // Gives a live entry `schedule`. An entry loaded with a `Refresh` keeps
+    // it, with the refresh-ahead point moved to match the new deadline.
+    fn set_schedule(&mut self, key: &K, schedule: Schedule) -> bool {
+        let now = self.clock.now();
+        let schedule = match self.data.get(key) {
+            Some(entry) if !entry.is_expired(now) => match entry.refresh {
+                Some(refresh) => schedule.with_refresh(refresh.settings(), now),
+                None => schedule,
+            },
+            _ => return false,
+        };
+        self.update_entry(key, |entry| {
+            entry.expiration = schedule.deadline;
+            entry.sliding = schedule.sliding;
+            entry.refresh = schedule.refresh;
+        });
+        true
+    }
+
+    // Pushes a sliding entry's deadline out after a read.
+    fn slide(&mut self, key: &K, now: Instant) {
+        let sliding = self.data.get(key).and_then(|entry| entry.sliding);
+        if let Some(sliding) = sliding {
+            self.update_entry(key, |entry| entry.expiration = sliding.deadline(now));
+        }
+    }
+
+    // Applies `update` to the entry for `key`, keeping the expiry index in
+    // step with its deadline and version.
+    fn update_entry<R>(&mut self, key: &K, update: impl FnOnce(&mut CacheEntry<V>) -> R) -> Option<R> {
+        let entry = self.data.get_mut(key)?;
+        let before = entry.dead_at().map(|dead_at| (dead_at, entry.version));
+        let result = update(entry);
+        let after = entry.dead_at().map(|dead_at| (dead_at, entry.version));
+        if before != after {
+            if let Some(before) = before {
+                self.expirations.remove(&before);
+            }
+            if let Some(after) = after {
+                self.expirations.insert(after, key.clone());
+            }
+        }
+        Some(result)
+    }
+
+    /// The version of the live entry for `key`.
+    pub fn version(&self, key: &K) -> Option<u64> {
+        let entry = self.data.get(key)?;
+        if entry.is_expired(self.clock.now()) {
+            return None;
+        }
+        Some(entry.version)
+    }
+
+    /// Stores `value` under `key` and returns the live value it replaced.
+    pub fn set(&mut self, key: K, value: V, expiry: Expiry) -> Result<Option<V>, CacheError> {
+        self.set_if(key, value, expiry, SetCondition::Always)
+            .map(|outcome| outcome.previous)
+    }
+
+    /// Writes only if `condition` holds for the live entry (expired entries
+    /// count as absent), checked and applied atomically.
+    pub fn set_if(
+        &mut self,
+        key: K,
+        value: V,
+        expiry: Expiry,
+        condition: SetCondition,
+    ) -> Result<SetOutcome<V>, CacheError> {
+        let current = self.version(&key);
+        let holds = match condition {
+            SetCondition::Always => true,
+            SetCondition::IfAbsent => current.is_none(),
+            SetCondition::IfPresent => current.is_some(),
+            SetCondition::IfVersion(version) => current == Some(version),
+        };
+        if !holds {
+            return Err(CacheError::ConditionFailed { current });
+        }
+        if current.is_none() {
+            self.remove_entry(&key, RemovalCause::Expired);
+        }
+        self.write(key, value, expiry.schedule(self.clock.now()))
+    }
+
+    // `set` for values loaded by `ShardedCache::get_with_refresh`.
+    pub(crate) fn set_refreshing(
+        &mut self,
+        key: K,
+        value: V,
+        expiry: Expiry,
+        refresh: Refresh,
+    ) -> Result<SetOutcome<V>, CacheError> {
+        if self.version(&key).is_none() {
+            self.remove_entry(&key, RemovalCause::Expired);
+        }
+        let now = self.clock.now();
+        self.write(key, value, expiry.schedule(now).with_refresh(refresh, now))
+    }
+
+    // Stores `value` with the given schedule, replacing any live entry in
+    // place and evicting others as needed to stay within budget.
+    fn write(&mut self, key: K, value: V, schedule: Schedule) -> Result<SetOutcome<V>, CacheError> {
+        let expiration = schedule.deadline;
+        let weight = self.weigher.weigh(&key, &value);
+        if let Some(max_weight) = self.max_weight {
+            if weight > max_weight {
+                return Err(CacheError::TooLarge { weight, max_weight });
+            }
+        }
+        let version = self.next_version;
+
+        if let Some(entry) = self.data.get(&key) {
+            let old_weight = entry.weight;
+            if self.over_budget(weight.saturating_sub(old_weight), 0) {
+                // Take the key out of the policy while making room so it
+                // cannot be chosen as its own victim.
+                self.policy.on_remove(&key);
+                self.weight -= old_weight;
+                self.evict(weight, 0);
+                self.weight += old_weight;
+                self.policy.on_insert(&key);
+            } else {
+                self.policy.on_access(&key);
+            }
+
+            self.next_version += 1;
+            let old = self.update_entry(&key, |entry| {
+                entry.expiration = expiration;
+                entry.version = version;
+                entry.weight = weight;
+                entry.sliding = schedule.sliding;
+                entry.refresh = schedule.refresh;
+                std::mem::replace(&mut entry.value, value)
+            });
+            self.weight = self.weight - old_weight + weight;
+            self.stats.overwrites += 1;
+            if let (Some(listener), Some(old)) = (&self.listener, &old) {
+                listener.on_removal(&key, old, RemovalCause::Replaced);
+            }
+            return Ok(SetOutcome { previous: old, version });
+        }
+
+        if self.max_size == 0 {
+            return Err(CacheError::ZeroCapacity);
+        }
+        self.evict(weight, 1);
+
+        self.next_version += 1;
+        let entry = CacheEntry {
+            value,
+            expiration,
+            version,
+            weight,
+            sliding: schedule.sliding,
+            refresh: schedule.refresh,
+        };
+        if let Some(dead_at) = entry.dead_at() {
+            self.expirations.insert((dead_at, version), key.clone());
+        }
+        self.policy.on_insert(&key);
+        self.weight += weight;
+        self.stats.inserts += 1;
+        self.data.insert(key, entry);
+        Ok(SetOutcome { previous: None, version })
+    }
+
+    /// Adds `delta` to the integer stored at `key` and returns the result.
+    ///
+    /// A missing key counts as zero and is created with `expiry`; an
+    /// existing one keeps its deadline.
+    pub fn incr_by(&mut self, key: K, delta: i64, expiry: Expiry) -> Result<i64, CacheError>
+        where
+            V: Counter,
+    {
+        let now = self.clock.now();
+        let (current, schedule) = match self.data.get(&key) {
+            Some(entry) if !entry.is_expired(now) => {
+                let current = entry.value.to_counter().ok_or(CacheError::NotAnInteger)?;
+                let schedule = Schedule {
+                    deadline: entry.expiration,
+                    sliding: entry.sliding,
+                    refresh: entry.refresh,
+                };
+                (current, schedule)
+            }
+            _ => {
+                self.remove_entry(&key, RemovalCause::Expired);
+                (0, expiry.schedule(now))
+            }
+        };
+        let value = current.checked_add(delta).ok_or(CacheError::Overflow)?;
+        self.write(key, V::from_counter(value), schedule)?;
+        Ok(value)
+    }
+
+    /// Subtracts `delta` from the integer stored at `key`, as
+    /// [`incr_by`](Cache::incr_by).
+    pub fn decr_by(&mut self, key: K, delta: i64, expiry: Expiry) -> Result<i64, CacheError>
+        where
+            V: Counter,
+    {
+        let delta = delta.checked_neg().ok_or(CacheError::Overflow)?;
+        self.incr_by(key, delta, expiry)
+    }
+
+    /// Writes only if the live entry has version `expected_version`.
+    pub fn compare_and_swap(
+        &mut self,
+        key: K,
+        expected_version: u64,
+        value: V,
+        expiry: Expiry,
+    ) -> Result<SetOutcome<V>, CacheError> {
+        self.set_if(key, value, expiry, SetCondition::IfVersion(expected_version))
+    }
+
+    /// Removes `key` and returns its value if it was live. An expired entry
+    /// is removed as [`RemovalCause::Expired`] and reported as missing.
+    pub fn delete(&mut self, key: &K) -> Option<V> {
+        if self.version(key).is_none() {
+            self.remove_entry(key, RemovalCause::Expired);
+            return None;
+        }
+        self.remove_entry(key, RemovalCause::Explicit).map(|entry| entry.value)
+    }
+
+    /// Returns the live value for `key`, or calls `loader` and stores what it
+    /// returns with `expiry`.
+    ///
+    /// Loader errors are returned as is and nothing is stored. The loaded
+    /// value is returned even if the cache cannot hold it, e.g. because it
+    /// is heavier than the weight budget.
+    pub fn get_with<E>(&mut self, key: K, expiry: Expiry, loader: impl FnOnce(&K) -> Result<V, E>) -> Result<V, E>
+        where
+            V: Clone,
+    {
+        if let Some(value) = self.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = loader(&key)?;
+        let _ = self.set(key, value.clone(), expiry);
+        Ok(value)
+    }
+
+    /// [`get`](Cache::get) for each key, in order.
+    pub fn get_many(&mut self, keys: &[K]) -> Vec<Option<V>>
+        where
+            V: Clone,
+    {
+        keys.iter().map(|key| self.get(key).cloned()).collect()
+    }
+
+    /// [`set`](Cache::set) for each item, in order.
+    pub fn set_many(&mut self, items: Vec<(K, V, Expiry)>) -> Vec<Result<Option<V>, CacheError>> {
+        items
+            .into_iter()
+            .map(|(key, value, expiry)| self.set(key, value, expiry))
+            .collect()
+    }
+
+    /// [`delete`](Cache::delete) for each key, in order.
+    pub fn delete_many(&mut self, keys: &[K]) -> Vec<Option<V>> {
+        keys.iter().map(|key| self.delete(key)).collect()
+    }
+
+    /// Removes up to `limit` entries whose deadline, and any window in which
+    /// they may be served stale, has passed. Returns how many were
+    /// reclaimed.
+    pub fn purge_expired(&mut self, limit: usize) -> usize {
+        let now = self.clock.now();
+        let mut purged = 0;
+        while purged < limit {
+            let key = match self.expirations.first_key_value() {
+                Some((&(dead_at, _), key)) if dead_at <= now => key.clone(),
+                _ => break,
+            };
+            self.remove_entry(&key, RemovalCause::Expired);
+            purged += 1;
+        }
+        purged
+    }
+
+    fn over_budget(&self, extra_weight: usize, extra_entries: usize) -> bool {
+        self.data.len() + extra_entries > self.max_size
+            || self
+                .max_weight
+                .is_some_and(|max_weight| self.weight + extra_weight > max_weight)
+    }
+
+    // Evicts victims chosen by the policy until an entry of `weight` (and
+    // `entries` more slots) fits.
+    fn evict(&mut self, weight: usize, entries: usize) {
+        self.evict_while(|cache| cache.over_budget(weight, entries));
+    }
+
+    // Evicts until at most `len` entries weighing at most `weight` are left,
+    // for a `ShardedCache` bringing all of its shards back within budget.
+    pub(crate) fn evict_down_to(&mut self, len: usize, weight: usize) {
+        self.evict_while(|cache| cache.data.len() > len || cache.weight > weight);
+    }
+
+    fn evict_while(&mut self, over: impl Fn(&Self) -> bool) {
+        while over(self) {
+            match self.policy.victim() {
+                Some(victim) => {
+                    let weight = self.data.get(&victim).map_or(0, |entry| entry.weight);
+                    self.remove_entry(&victim, RemovalCause::Evicted(weight));
+                }
+                None => break,
+            }
+        }
+    }
+
+    // Every removal goes through here so the weight, policy, expiry index
+    // and listener all hear about it.
+    fn remove_entry(&mut self, key: &K, cause: RemovalCause) -> Option<CacheEntry<V>> {
+        let entry = self.data.remove(key)?;
+        match cause {
+            RemovalCause::Evicted(_) => self.stats.evictions += 1,
+            RemovalCause::Explicit => self.stats.deletes += 1,
+            RemovalCause::Expired | RemovalCause::Replaced => {}
+        }
+        if let Some(listener) = &self.listener {
+            listener.on_removal(key, &entry.value, cause);
+        }
+        self.weight -= entry.weight;
+        self.policy.on_remove(key);
+        if let Some(dead_at) = entry.dead_at() {
+            self.expirations.remove(&(dead_at, entry.version));
+        }
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::listener::ChannelListener;
+
+    #[test]
+    fn overwrite_updates_in_place() {
+        let mut cache = Cache::new(2);
+        cache.set("a", 1, Expiry::Never).unwrap();
+        cache.set("b", 2, Expiry::Never).unwrap();
+
+        assert_eq!(cache.set("a", 3, Expiry::Never).unwrap(), Some(1));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.stats().evictions, 0);
+        assert_eq!(cache.peek(&"a"), Some(&3));
+        assert_eq!(cache.peek(&"b"), Some(&2));
+    }
+
+    #[test]
+    fn overwrite_refreshes_recency() {
+        let mut cache = Cache::new(2);
+        cache.set("a", 1, Expiry::Never).unwrap();
+        cache.set("b", 2, Expiry::Never).unwrap();
+        cache.set("a", 3, Expiry::Never).unwrap();
+
+        cache.set("c", 4, Expiry::Never).unwrap();
+        assert!(cache.contains(&"a"));
+        assert!(!cache.contains(&"b"));
+        assert!(cache.contains(&"c"));
+    }
+
+    #[test]
+    fn overwrite_replaces_ttl_and_version() {
+        let mut cache = Cache::new(2);
+        cache.set("a", 1, Expiry::After(Duration::from_secs(60))).unwrap();
+        let version = cache.version(&"a").unwrap();
+
+        cache.set("a", 2, Expiry::Never).unwrap();
+        assert_eq!(cache.ttl(&"a"), Some(None));
+        assert!(cache.version(&"a").unwrap() > version);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let mut cache = Cache::new(3);
+        for (key, value) in [("a", 1), ("b", 2), ("c", 3)] {
+            cache.set(key, value, Expiry::Never).unwrap();
+        }
+        cache.get(&"a");
+
+        cache.set("d", 4, Expiry::Never).unwrap();
+        assert!(!cache.contains(&"b"));
+        cache.set("e", 5, Expiry::Never).unwrap();
+        assert!(!cache.contains(&"c"));
+        cache.set("f", 6, Expiry::Never).unwrap();
+        assert!(!cache.contains(&"a"));
+
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.stats().evictions, 3);
+    }
+
+    #[test]
+    fn peek_does_not_refresh_recency() {
+        let mut cache = Cache::new(2);
+        cache.set("a", 1, Expiry::Never).unwrap();
+        cache.set("b", 2, Expiry::Never).unwrap();
+        cache.peek(&"a");
+
+        cache.set("c", 3, Expiry::Never).unwrap();
+        assert!(!cache.contains(&"a"));
+    }
+
+    #[test]
+    fn unrepresentable_deadlines_never_expire() {
+        let mut cache = Cache::new(3);
+        let forever = Duration::from_secs(u64::MAX);
+        cache.set("after", 1, Expiry::After(forever)).unwrap();
+        cache
+            .set(
+                "sliding",
+                2,
+                Expiry::Sliding {
+                    ttl: forever,
+                    max_lifetime: Some(forever),
+                },
+            )
+            .unwrap();
+        let refresh = Refresh {
+            ahead: Some(0.5),
+            stale_while_revalidate: forever,
+            stale_if_error: forever,
+        };
+        cache
+            .set_refreshing("stale", 3, Expiry::After(Duration::from_secs(60)), refresh)
+            .unwrap();
+
+        assert_eq!(cache.get(&"sliding"), Some(&2));
+        assert_eq!(cache.ttl(&"after"), Some(None));
+        assert_eq!(cache.ttl(&"sliding"), Some(None));
+        assert!(cache.ttl(&"stale").unwrap().is_some());
+        assert!(cache.expire(&"after", Expiry::After(forever)));
+        assert_eq!(cache.purge_expired(10), 0);
+    }
+
+    #[test]
+    fn if_absent_writes_only_missing_or_expired_keys() {
+        let mut cache = Cache::new(3);
+        cache.set("a", 1, Expiry::Never).unwrap();
+        let version = cache.version(&"a");
+        assert!(matches!(
+            cache.set_if("a", 2, Expiry::Never, SetCondition::IfAbsent),
+            Err(CacheError::ConditionFailed { current }) if current == version
+        ));
+        assert_eq!(cache.peek(&"a"), Some(&1));
+
+        cache.set_if("b", 2, Expiry::Never, SetCondition::IfAbsent).unwrap();
+        cache.expire_at(&"b", Instant::now());
+        let outcome = cache.set_if("b", 3, Expiry::Never, SetCondition::IfAbsent).unwrap();
+        assert_eq!(outcome.previous, None);
+        assert_eq!(cache.peek(&"b"), Some(&3));
+    }
+
+    #[test]
+    fn if_present_writes_only_live_keys() {
+        let mut cache = Cache::new(3);
+        assert!(matches!(
+            cache.set_if("a", 1, Expiry::Never, SetCondition::IfPresent),
+            Err(CacheError::ConditionFailed { current: None })
+        ));
+        assert!(cache.is_empty());
+
+        cache.set("a", 1, Expiry::Never).unwrap();
+        let outcome = cache.set_if("a", 2, Expiry::Never, SetCondition::IfPresent).unwrap();
+        assert_eq!(outcome.previous, Some(1));
+
+        cache.expire_at(&"a", Instant::now());
+        assert!(matches!(
+            cache.set_if("a", 3, Expiry::Never, SetCondition::IfPresent),
+            Err(CacheError::ConditionFailed { current: None })
+        ));
+    }
+
+    #[test]
+    fn compare_and_swap_needs_the_current_version() {
+        let mut cache = Cache::new(3);
+        let first = cache.set_if("a", 1, Expiry::Never, SetCondition::Always).unwrap().version;
+        let second = cache.compare_and_swap("a", first, 2, Expiry::Never).unwrap();
+        assert_eq!(second.previous, Some(1));
+        assert_ne!(second.version, first);
+
+        assert!(matches!(
+            cache.compare_and_swap("a", first, 3, Expiry::Never),
+            Err(CacheError::ConditionFailed { current: Some(current) }) if current == second.version
+        ));
+        assert_eq!(cache.peek(&"a"), Some(&2));
+        assert!(matches!(
+            cache.compare_and_swap("b", first, 3, Expiry::Never),
+            Err(CacheError::ConditionFailed { current: None })
+        ));
+    }
+
+    #[test]
+    fn versions_are_never_reused() {
+        let mut cache = Cache::new(3);
+        let first = cache.set_if("a", 1, Expiry::Never, SetCondition::Always).unwrap().version;
+        cache.delete(&"a");
+        let second = cache.set_if("a", 1, Expiry::Never, SetCondition::Always).unwrap().version;
+        assert!(second > first);
+        assert!(matches!(
+            cache.compare_and_swap("a", first, 2, Expiry::Never),
+            Err(CacheError::ConditionFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn incr_creates_missing_counters_with_the_given_expiry() {
+        let mut cache: Cache<&str, String> = Cache::new(3);
+        assert_eq!(cache.incr_by("n", 5, Expiry::After(Duration::from_secs(60))).unwrap(), 5);
+        assert_eq!(cache.peek(&"n").map(String::as_str), Some("5"));
+        assert!(cache.ttl(&"n").unwrap().is_some());
+        assert_eq!(cache.decr_by("m", 2, Expiry::Never).unwrap(), -2);
+    }
+
+    #[test]
+    fn incr_keeps_the_existing_ttl() {
+        let mut cache: Cache<&str, String> = Cache::new(3);
+        cache.set("n", "1".to_string(), Expiry::After(Duration::from_secs(60))).unwrap();
+        assert_eq!(cache.incr_by("n", 1, Expiry::Never).unwrap(), 2);
+        assert!(cache.ttl(&"n").unwrap().unwrap() <= Duration::from_secs(60));
+
+        cache.set("p", "1".to_string(), Expiry::Never).unwrap();
+        assert_eq!(cache.decr_by("p", 1, Expiry::After(Duration::from_secs(60))).unwrap(), 0);
+        assert_eq!(cache.ttl(&"p"), Some(None));
+    }
+
+    #[test]
+    fn counters_do_not_overflow() {
+        let mut cache: Cache<&str, String> = Cache::new(3);
+        cache.set("max", i64::MAX.to_string(), Expiry::Never).unwrap();
+        assert!(matches!(cache.incr_by("max", 1, Expiry::Never), Err(CacheError::Overflow)));
+        assert_eq!(cache.peek(&"max"), Some(&i64::MAX.to_string()));
+
+        cache.set("min", i64::MIN.to_string(), Expiry::Never).unwrap();
+        assert!(matches!(cache.decr_by("min", 1, Expiry::Never), Err(CacheError::Overflow)));
+        assert!(matches!(cache.decr_by("zero", i64::MIN, Expiry::Never), Err(CacheError::Overflow)));
+        assert!(!cache.contains(&"zero"));
+        assert_eq!(cache.incr_by("min", i64::MAX, Expiry::Never).unwrap(), -1);
+    }
+
+    #[test]
+    fn counters_need_integers() {
+        let mut cache: Cache<&str, String> = Cache::new(3);
+        cache.set("s", "one".to_string(), Expiry::Never).unwrap();
+        assert!(matches!(cache.incr_by("s", 1, Expiry::Never), Err(CacheError::NotAnInteger)));
+        assert_eq!(cache.peek(&"s").map(String::as_str), Some("one"));
+    }
+
+    fn sliding(ttl_ms: u64, max_lifetime_ms: Option<u64>) -> Expiry {
+        Expiry::Sliding {
+            ttl: Duration::from_millis(ttl_ms),
+            max_lifetime: max_lifetime_ms.map(Duration::from_millis),
+        }
+    }
+
+    // A cache whose time only moves when the returned clock is advanced.
+    fn clocked<V>(capacity: usize) -> (Cache<&'static str, V>, Arc<ManualClock>) {
+        let clock = ManualClock::new();
+        (Cache::new(capacity).with_clock(clock.clone()), clock)
+    }
+
+    #[test]
+    fn sliding_reads_extend_the_deadline() {
+        let (mut cache, clock) = clocked(3);
+        cache.set("a", 1, sliding(100, None)).unwrap();
+        clock.advance_ms(60);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        clock.advance_ms(60);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        clock.advance_ms(120);
+        assert_eq!(cache.get(&"a"), None);
+    }
+
+    #[test]
+    fn sliding_stops_at_the_max_lifetime() {
+        let (mut cache, clock) = clocked(3);
+        cache.set("a", 1, sliding(100, Some(150))).unwrap();
+        clock.advance_ms(60);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        clock.advance_ms(60);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        assert!(cache.ttl(&"a").unwrap().unwrap() <= Duration::from_millis(30));
+        clock.advance_ms(60);
+        assert_eq!(cache.purge_expired(10), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn peek_does_not_slide() {
+        let (mut cache, clock) = clocked(3);
+        cache.set("a", 1, sliding(100, None)).unwrap();
+        clock.advance_ms(60);
+        assert_eq!(cache.peek(&"a"), Some(&1));
+        assert!(cache.contains(&"a"));
+        clock.advance_ms(60);
+        assert_eq!(cache.peek(&"a"), None);
+    }
+
+    #[test]
+    fn stale_windows_outlive_the_ttl_until_purged() {
+        let (mut cache, clock) = clocked(10);
+        let refresh = Refresh {
+            ahead: Some(0.5),
+            stale_while_revalidate: Duration::from_millis(50),
+            stale_if_error: Duration::from_millis(100),
+        };
+        cache.set_refreshing("a", 1, Expiry::After(Duration::from_millis(100)), refresh).unwrap();
+        assert!(matches!(cache.lookup(&"a"), Lookup::Fresh(1)));
+        clock.advance_ms(60);
+        assert!(matches!(cache.lookup(&"a"), Lookup::RefreshDue(1)));
+        clock.advance_ms(60);
+        assert_eq!(cache.get(&"a"), None);
+        assert!(matches!(cache.lookup(&"a"), Lookup::StaleWhileRevalidate(1)));
+        assert_eq!(cache.purge_expired(10), 0);
+        clock.advance_ms(50);
+        assert!(matches!(cache.lookup(&"a"), Lookup::StaleIfError(1)));
+        clock.advance_ms(50);
+        assert_eq!(cache.purge_expired(10), 1);
+        assert_eq!(cache.len(), 0);
+        assert!(matches!(cache.lookup(&"a"), Lookup::Miss));
+    }
+
+    #[test]
+    fn new_expiries_keep_refresh_settings() {
+        let secs = Duration::from_secs;
+        let (mut cache, clock) = clocked(10);
+        let refresh = Refresh {
+            ahead: Some(0.5),
+            stale_while_revalidate: secs(10),
+            stale_if_error: secs(20),
+        };
+        cache.set_refreshing("a", 1, Expiry::After(secs(100)), refresh).unwrap();
+        let refreshing = |cache: &Cache<&str, i32>| cache.data[&"a"].refresh.unwrap();
+        let loaded_at = clock.now();
+
+        assert!(cache.expire(&"a", Expiry::After(secs(1000))));
+        assert_eq!(refreshing(&cache).settings(), refresh);
+        assert_eq!(refreshing(&cache).refresh_at, Some(loaded_at + secs(500)));
+
+        clock.advance_ms(60_000);
+        assert!(cache.touch(&"a", Some(Expiry::After(secs(10)))));
+        assert_eq!(refreshing(&cache).refresh_at, Some(clock.now() + secs(5)));
+
+        // Without a deadline there is nothing to refresh ahead of.
+        assert!(cache.persist(&"a"));
+        assert_eq!(refreshing(&cache).settings(), refresh);
+        assert_eq!(refreshing(&cache).refresh_at, None);
+        assert!(matches!(cache.lookup(&"a"), Lookup::Fresh(1)));
+    }
+
+    // A cache of `capacity` entries weighing their value, whose removals
+    // end up in the returned log.
+    #[allow(clippy::type_complexity)]
+    fn logged(capacity: usize) -> (Cache<&'static str, i32>, Arc<Mutex<Vec<(&'static str, i32, RemovalCause)>>>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&log);
+        let listener = move |key: &&'static str, value: &i32, cause: RemovalCause| {
+            sink.lock().unwrap().push((*key, *value, cause));
+        };
+        let cache = Cache::new(capacity)
+            .with_weigher(capacity * 10, |_: &&str, value: &i32| *value as usize)
+            .with_removal_listener(Arc::new(listener));
+        (cache, log)
+    }
+
+    #[test]
+    fn listener_hears_every_removal_cause() {
+        let (mut cache, log) = logged(2);
+        cache.set("a", 1, Expiry::Never).unwrap();
+        cache.set("a", 2, Expiry::Never).unwrap();
+        cache.set("b", 3, Expiry::Never).unwrap();
+        cache.set("c", 4, Expiry::Never).unwrap();
+        cache.delete(&"b");
+        cache.delete(&"b");
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                ("a", 1, RemovalCause::Replaced),
+                ("a", 2, RemovalCause::Evicted(2)),
+                ("b", 3, RemovalCause::Explicit),
+            ]
+        );
+    }
+
+    #[test]
+    fn listener_hears_expiry_on_read_and_on_purge() {
+        let (cache, log) = logged(10);
+        let clock = ManualClock::new();
+        let mut cache = cache.with_clock(clock.clone());
+        let expiry = Expiry::After(Duration::from_millis(20));
+        cache.set("a", 1, expiry).unwrap();
+        cache.set("b", 2, expiry).unwrap();
+        clock.advance_ms(30);
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.purge_expired(10), 1);
+        assert_eq!(
+            *log.lock().unwrap(),
+            [("a", 1, RemovalCause::Expired), ("b", 2, RemovalCause::Expired)]
+        );
+    }
+
+    #[test]
+    fn deleting_an_expired_key_reports_a_miss() {
+        let (cache, log) = logged(10);
+        let clock = ManualClock::new();
+        let mut cache = cache.with_clock(clock.clone());
+        cache.set("a", 1, Expiry::After(Duration::from_millis(20))).unwrap();
+        clock.advance_ms(30);
+        assert_eq!(cache.delete(&"a"), None);
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.stats().deletes, 0);
+        assert_eq!(*log.lock().unwrap(), [("a", 1, RemovalCause::Expired)]);
+    }
+
+    #[test]
+    fn channel_listener_counts_what_it_drops() {
+        let (listener, mut removals) = ChannelListener::new(1);
+        let listener = Arc::new(listener);
+        let shared: Arc<dyn RemovalListener<_, _>> = listener.clone();
+        let mut cache = Cache::new(10).with_removal_listener(shared);
+        cache.set("a", 1, Expiry::Never).unwrap();
+        cache.set("b", 2, Expiry::Never).unwrap();
+        cache.delete(&"a");
+        cache.delete(&"b");
+
+        let removal = removals.try_recv().unwrap();
+        assert_eq!((removal.key, removal.value, removal.cause), ("a", 1, RemovalCause::Explicit));
+        assert!(removals.try_recv().is_err());
+        assert_eq!(listener.dropped(), 1);
+    }
+
+    #[test]
+    fn zero_capacity_stores_nothing() {
+        let mut cache: Cache<&str, i32> = Cache::new(0);
+        assert!(matches!(cache.set("a", 1, Expiry::Never), Err(CacheError::ZeroCapacity)));
+        assert!(matches!(
+            cache.set_if("a", 1, Expiry::Never, SetCondition::IfAbsent),
+            Err(CacheError::ZeroCapacity)
+        ));
+
+        assert!(cache.is_empty());
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.next_version, 1);
+        assert_eq!(cache.stats().inserts, 0);
+    }
+}
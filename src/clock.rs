@@ -0,0 +1,51 @@
+use std::time::Instant;
+
+/// Where a [`Cache`](crate::Cache) gets the current time for expiry.
+///
+/// The default is [`SystemClock`]; supplying another one lets tests move
+/// time forward instead of sleeping. Any `Fn() -> Instant` closure is a
+/// clock.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+}
+
+impl<F> Clock for F
+    where
+        F: Fn() -> Instant + Send + Sync,
+{
+    fn now(&self) -> Instant {
+        self()
+    }
+}
+
+/// The operating system's monotonic clock, as read by [`Instant::now`].
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// A clock for tests that stands still until it is advanced.
+#[cfg(test)]
+pub(crate) struct ManualClock(std::sync::Mutex<Instant>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub(crate) fn new() -> std::sync::Arc<Self> {
+        std::sync::Arc::new(ManualClock(std::sync::Mutex::new(Instant::now())))
+    }
+
+    pub(crate) fn advance_ms(&self, ms: u64) {
+        *self.0.lock().unwrap() += std::time::Duration::from_millis(ms);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
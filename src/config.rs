@@ -0,0 +1,466 @@
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use hyper::Uri;
+use raw_cache::{EvictionPolicy, FifoPolicy, LfuPolicy, LruPolicy, RandomPolicy, TinyLfuPolicy};
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicyKind {
+    Lru,
+    Lfu,
+    Fifo,
+    Random,
+    TinyLfu,
+}
+
+impl EvictionPolicyKind {
+    pub fn build<K>(self, capacity: usize) -> Box<dyn EvictionPolicy<K> + Send + Sync>
+        where
+            K: Eq + Hash + Clone + Send + Sync + 'static,
+    {
+        match self {
+            EvictionPolicyKind::Lru => Box::new(LruPolicy::new(capacity)),
+            EvictionPolicyKind::Lfu => Box::new(LfuPolicy::new(capacity)),
+            EvictionPolicyKind::Fifo => Box::new(FifoPolicy::new(capacity)),
+            EvictionPolicyKind::Random => Box::new(RandomPolicy::new(capacity)),
+            EvictionPolicyKind::TinyLfu => Box::new(TinyLfuPolicy::new(capacity)),
+        }
+    }
+}
+
+impl std::str::FromStr for EvictionPolicyKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lru" => Ok(EvictionPolicyKind::Lru),
+            "lfu" => Ok(EvictionPolicyKind::Lfu),
+            "fifo" => Ok(EvictionPolicyKind::Fifo),
+            "random" => Ok(EvictionPolicyKind::Random),
+            "tinylfu" | "w-tinylfu" => Ok(EvictionPolicyKind::TinyLfu),
+            other => Err(format!("unknown eviction policy `{}`", other)),
+        }
+    }
+}
+
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+const USAGE: &str = "\
+Usage: raw-cache [OPTIONS]
+
+Options:
+    --config <PATH>             TOML configuration file
+    --capacity <N>              maximum number of entries
+    --max-bytes <N>             maximum total size of keys and values (0 = unlimited)
+    --shards <N>                number of independently locked shards (0 = one per CPU)
+    --address <IP>              listen address
+    --port <PORT>               listen port
+    --default-ttl-secs <SECS>   TTL used when a request sets none (0 = never expire)
+    --max-ttl-secs <SECS>       largest TTL a request may ask for (0 = unlimited)
+    --eviction-policy <NAME>    eviction policy (lru, lfu, fifo, random or tinylfu)
+    --max-batch-size <N>        most keys accepted by /mget, /mset and /mdel
+    --sweep-interval-ms <MS>    how often expired entries are reclaimed (0 = only on read)
+    --log-level <LEVEL>         off, error, warn, info, debug or trace
+    --upstream-url <URL>        http:// base URL that GET misses are loaded from
+    --refresh-ahead <FRACTION>  reload upstream values in the background once this
+                                fraction of their TTL has passed (0 = off)
+    --stale-while-revalidate-secs <SECS>
+                                serve expired upstream values this long while reloading
+    --stale-if-error-secs <SECS>
+                                serve expired upstream values this long if reloading fails
+    --help                      print this message
+
+Every option can also be set in the config file or through a RAW_CACHE_*
+environment variable (e.g. RAW_CACHE_CAPACITY). Flags override environment
+variables, which override the config file.
+";
+
+// Settings are layered: built-in defaults, then the TOML file, then
+// `RAW_CACHE_*` environment variables, then command-line flags.
+#[derive(Debug)]
+pub struct Config {
+    pub capacity: usize,
+    pub max_bytes: Option<usize>,
+    pub shards: Option<usize>,
+    pub address: IpAddr,
+    pub port: u16,
+    pub default_ttl: Option<Duration>,
+    pub max_ttl: Option<Duration>,
+    pub eviction_policy: EvictionPolicyKind,
+    pub max_batch_size: usize,
+    pub sweep_interval: Option<Duration>,
+    pub log_level: String,
+    pub upstream_url: Option<Uri>,
+    pub refresh_ahead: Option<f64>,
+    pub stale_while_revalidate: Duration,
+    pub stale_if_error: Duration,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    capacity: Option<usize>,
+    max_bytes: Option<usize>,
+    shards: Option<usize>,
+    address: Option<IpAddr>,
+    port: Option<u16>,
+    default_ttl_secs: Option<u64>,
+    max_ttl_secs: Option<u64>,
+    eviction_policy: Option<String>,
+    max_batch_size: Option<usize>,
+    sweep_interval_ms: Option<u64>,
+    log_level: Option<String>,
+    upstream_url: Option<String>,
+    refresh_ahead: Option<f64>,
+    stale_while_revalidate_secs: Option<u64>,
+    stale_if_error_secs: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            capacity: 3,
+            max_bytes: None,
+            shards: None,
+            address: IpAddr::from([127, 0, 0, 1]),
+            port: 3030,
+            default_ttl: Some(Duration::from_secs(5)),
+            max_ttl: None,
+            eviction_policy: EvictionPolicyKind::Lru,
+            max_batch_size: 1000,
+            sweep_interval: Some(Duration::from_secs(1)),
+            log_level: "info".to_string(),
+            upstream_url: None,
+            refresh_ahead: None,
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+        }
+    }
+}
+
+fn ttl_from_secs(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
+fn interval_from_millis(ms: u64) -> Option<Duration> {
+    if ms == 0 {
+        None
+    } else {
+        Some(Duration::from_millis(ms))
+    }
+}
+
+// An empty URL turns read-through off.
+fn upstream_from_str(url: &str) -> Result<Option<Uri>, String> {
+    if url.is_empty() {
+        return Ok(None);
+    }
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| format!("invalid upstream URL `{}`: {}", url, e))?;
+    if uri.scheme_str() != Some("http") || uri.authority().is_none() {
+        return Err(format!("upstream URL `{}` must be an absolute http:// URL", url));
+    }
+    Ok(Some(uri))
+}
+
+impl Config {
+    const SETTINGS: [&'static str; 15] = [
+        "capacity",
+        "max_bytes",
+        "shards",
+        "address",
+        "port",
+        "default_ttl_secs",
+        "max_ttl_secs",
+        "eviction_policy",
+        "max_batch_size",
+        "sweep_interval_ms",
+        "log_level",
+        "upstream_url",
+        "refresh_ahead",
+        "stale_while_revalidate_secs",
+        "stale_if_error_secs",
+    ];
+
+    pub fn load() -> Result<Config, String> {
+        Config::load_from(std::env::args().skip(1), |var| std::env::var(var).ok())
+    }
+
+    // `load` with the command-line arguments (without the program name) and
+    // environment lookups passed in.
+    fn load_from(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, String> {
+        let mut flags = Vec::new();
+        let mut config_path = env("RAW_CACHE_CONFIG");
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                print!("{}", USAGE);
+                std::process::exit(0);
+            }
+            let name = arg
+                .strip_prefix("--")
+                .ok_or_else(|| format!("unexpected argument `{}`", arg))?;
+            let (name, value) = match name.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("missing value for `--{}`", name))?;
+                    (name.to_string(), value)
+                }
+            };
+            let setting = name.replace('-', "_");
+            if setting == "config" {
+                config_path = Some(value);
+            } else if Self::SETTINGS.contains(&setting.as_str()) {
+                flags.push((setting, value));
+            } else {
+                return Err(format!("unknown flag `--{}`", name));
+            }
+        }
+
+        let mut config = Config::default();
+        if let Some(path) = config_path {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| format!("cannot read config file {}: {}", path, e))?;
+            let file: ConfigFile =
+                toml::from_str(&contents).map_err(|e| format!("invalid config file {}: {}", path, e))?;
+            config.apply_file(file)?;
+        }
+
+        for setting in Self::SETTINGS {
+            let var = format!("RAW_CACHE_{}", setting.to_ascii_uppercase());
+            if let Some(value) = env(&var) {
+                config
+                    .apply(setting, &value)
+                    .map_err(|e| format!("{}: {}", var, e))?;
+            }
+        }
+
+        for (setting, value) in flags {
+            config
+                .apply(&setting, &value)
+                .map_err(|e| format!("--{}: {}", setting.replace('_', "-"), e))?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_file(&mut self, file: ConfigFile) -> Result<(), String> {
+        if let Some(capacity) = file.capacity {
+            self.capacity = capacity;
+        }
+        if let Some(bytes) = file.max_bytes {
+            self.max_bytes = Some(bytes).filter(|&bytes| bytes > 0);
+        }
+        if let Some(shards) = file.shards {
+            self.shards = Some(shards).filter(|&shards| shards > 0);
+        }
+        if let Some(address) = file.address {
+            self.address = address;
+        }
+        if let Some(port) = file.port {
+            self.port = port;
+        }
+        if let Some(secs) = file.default_ttl_secs {
+            self.default_ttl = ttl_from_secs(secs);
+        }
+        if let Some(secs) = file.max_ttl_secs {
+            self.max_ttl = ttl_from_secs(secs);
+        }
+        if let Some(policy) = file.eviction_policy {
+            self.eviction_policy = policy.parse()?;
+        }
+        if let Some(size) = file.max_batch_size {
+            self.max_batch_size = size;
+        }
+        if let Some(ms) = file.sweep_interval_ms {
+            self.sweep_interval = interval_from_millis(ms);
+        }
+        if let Some(level) = file.log_level {
+            self.log_level = level;
+        }
+        if let Some(url) = file.upstream_url {
+            self.upstream_url = upstream_from_str(&url)?;
+        }
+        if let Some(fraction) = file.refresh_ahead {
+            self.refresh_ahead = Some(fraction).filter(|&fraction| fraction != 0.0);
+        }
+        if let Some(secs) = file.stale_while_revalidate_secs {
+            self.stale_while_revalidate = Duration::from_secs(secs);
+        }
+        if let Some(secs) = file.stale_if_error_secs {
+            self.stale_if_error = Duration::from_secs(secs);
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, setting: &str, value: &str) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String>
+            where
+                T::Err: std::fmt::Display,
+        {
+            value.parse().map_err(|e| format!("invalid value `{}`: {}", value, e))
+        }
+
+        match setting {
+            "capacity" => self.capacity = parse(value)?,
+            "max_bytes" => self.max_bytes = Some(parse(value)?).filter(|&bytes| bytes > 0),
+            "shards" => self.shards = Some(parse(value)?).filter(|&shards| shards > 0),
+            "address" => self.address = parse(value)?,
+            "port" => self.port = parse(value)?,
+            "default_ttl_secs" => self.default_ttl = ttl_from_secs(parse(value)?),
+            "max_ttl_secs" => self.max_ttl = ttl_from_secs(parse(value)?),
+            "eviction_policy" => self.eviction_policy = value.parse()?,
+            "max_batch_size" => self.max_batch_size = parse(value)?,
+            "sweep_interval_ms" => self.sweep_interval = interval_from_millis(parse(value)?),
+            "log_level" => self.log_level = value.to_string(),
+            "upstream_url" => self.upstream_url = upstream_from_str(value)?,
+            "refresh_ahead" => self.refresh_ahead = Some(parse(value)?).filter(|&fraction| fraction != 0.0),
+            "stale_while_revalidate_secs" => self.stale_while_revalidate = Duration::from_secs(parse(value)?),
+            "stale_if_error_secs" => self.stale_if_error = Duration::from_secs(parse(value)?),
+            _ => return Err(format!("unknown setting `{}`", setting)),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.capacity == 0 {
+            return Err("capacity must be greater than zero".to_string());
+        }
+        if self.max_batch_size == 0 {
+            return Err("max_batch_size must be greater than zero".to_string());
+        }
+        if self
+            .refresh_ahead
+            .is_some_and(|fraction| !(fraction > 0.0 && fraction < 1.0))
+        {
+            return Err("refresh_ahead must be between 0 and 1".to_string());
+        }
+        if self.shards.is_some_and(|shards| shards > self.capacity) {
+            return Err("shards must not exceed capacity".to_string());
+        }
+        if !LOG_LEVELS.contains(&self.log_level.to_ascii_lowercase().as_str()) {
+            return Err(format!(
+                "log_level must be one of {}, got `{}`",
+                LOG_LEVELS.join(", "),
+                self.log_level
+            ));
+        }
+        match (self.default_ttl, self.max_ttl) {
+            (None, Some(_)) => Err("default_ttl_secs must be set when max_ttl_secs is".to_string()),
+            (Some(default), Some(max)) if default > max => {
+                Err("default_ttl_secs must not exceed max_ttl_secs".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn load(args: &[&str], env: &[(&str, &str)]) -> Result<Config, String> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+            .collect();
+        Config::load_from(args.iter().map(|arg| arg.to_string()), |var| env.get(var).cloned())
+    }
+
+    // Writes `contents` to a config file named after the calling test.
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("raw-cache-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn defaults_need_no_settings() {
+        let config = load(&[], &[]).unwrap();
+        assert_eq!(config.capacity, Config::default().capacity);
+        assert_eq!(config.port, 3030);
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let path = config_file("precedence", "capacity = 10\nport = 4000\nmax_batch_size = 7\n");
+        let path = path.to_str().unwrap();
+        let env = [
+            ("RAW_CACHE_CONFIG", path),
+            ("RAW_CACHE_CAPACITY", "20"),
+            ("RAW_CACHE_PORT", "5000"),
+        ];
+        let config = load(&["--capacity", "30"], &env).unwrap();
+        assert_eq!((config.capacity, config.port, config.max_batch_size), (30, 5000, 7));
+
+        let config = load(&[], &env).unwrap();
+        assert_eq!((config.capacity, config.port, config.max_batch_size), (20, 5000, 7));
+
+        let env = [("RAW_CACHE_CONFIG", "/nonexistent/raw-cache.toml")];
+        let config = load(&["--config", path, "--port=6000"], &env).unwrap();
+        assert_eq!((config.capacity, config.port), (10, 6000));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn zero_turns_optional_limits_off() {
+        let config = load(&["--max-bytes", "0", "--max-ttl-secs=0", "--sweep-interval-ms", "0"], &[]).unwrap();
+        assert_eq!((config.max_bytes, config.max_ttl, config.sweep_interval), (None, None, None));
+    }
+
+    #[test]
+    fn bad_arguments_name_their_source() {
+        assert_eq!(load(&["capacity"], &[]).unwrap_err(), "unexpected argument `capacity`");
+        assert_eq!(load(&["--capacity"], &[]).unwrap_err(), "missing value for `--capacity`");
+        assert_eq!(load(&["--size", "1"], &[]).unwrap_err(), "unknown flag `--size`");
+        assert!(load(&["--port", "x"], &[]).unwrap_err().starts_with("--port: invalid value `x`"));
+        assert!(load(&[], &[("RAW_CACHE_PORT", "x")])
+            .unwrap_err()
+            .starts_with("RAW_CACHE_PORT: invalid value `x`"));
+
+        let path = config_file("unknown", "size = 1\n");
+        assert!(load(&["--config", path.to_str().unwrap()], &[])
+            .unwrap_err()
+            .starts_with("invalid config file"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_combinations_are_rejected() {
+        let cases: [(&[&str], &str); 7] = [
+            (&["--capacity", "0"], "capacity must be greater than zero"),
+            (&["--max-batch-size", "0"], "max_batch_size must be greater than zero"),
+            (&["--refresh-ahead", "1.5"], "refresh_ahead must be between 0 and 1"),
+            (&["--capacity", "2", "--shards", "3"], "shards must not exceed capacity"),
+            (&["--log-level", "loud"], "log_level must be one of off, error, warn, info, debug, trace, got `loud`"),
+            (
+                &["--default-ttl-secs", "0", "--max-ttl-secs", "10"],
+                "default_ttl_secs must be set when max_ttl_secs is",
+            ),
+            (
+                &["--default-ttl-secs", "20", "--max-ttl-secs", "10"],
+                "default_ttl_secs must not exceed max_ttl_secs",
+            ),
+        ];
+        for (args, error) in cases {
+            assert_eq!(load(args, &[]).unwrap_err(), error, "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::time::{Duration, Instant};
+
+/// When an entry stops being visible.
+///
+/// A deadline too far in the future for [`Instant`] to represent is treated
+/// as no deadline at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expiry {
+    /// The entry lives until it is evicted or removed.
+    Never,
+    /// The entry expires a fixed time after it is written.
+    After(Duration),
+    /// The entry expires `ttl` after the last read, but never later than
+    /// `max_lifetime` after being written.
+    Sliding {
+        /// How long the entry lives after each read.
+        ttl: Duration,
+        /// The cap on the entry's total lifetime, if any.
+        max_lifetime: Option<Duration>,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Sliding {
+    ttl: Duration,
+    hard_deadline: Option<Instant>,
+}
+
+impl Sliding {
+    pub(crate) fn deadline(self, now: Instant) -> Option<Instant> {
+        match (now.checked_add(self.ttl), self.hard_deadline) {
+            (Some(deadline), Some(hard)) => Some(deadline.min(hard)),
+            (deadline, hard) => deadline.or(hard),
+        }
+    }
+}
+
+/// Keeps an entry loaded by
+/// [`ShardedCache::get_with_refresh`](crate::ShardedCache::get_with_refresh)
+/// available around its expiry, so readers are not stalled by reloads.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Refresh {
+    /// Reload in the background once this fraction of the entry's TTL has
+    /// passed. Values outside `0.0..1.0` (exclusive) turn refresh-ahead off.
+    pub ahead: Option<f64>,
+    /// How long after expiry the old value is still served while a
+    /// background reload runs.
+    pub stale_while_revalidate: Duration,
+    /// How long after expiry the old value is served when reloading it
+    /// fails.
+    pub stale_if_error: Duration,
+}
+
+// Per-entry state for a `Refresh`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Refreshing {
+    pub(crate) refresh_at: Option<Instant>,
+    pub(crate) ahead: Option<f64>,
+    pub(crate) stale_while_revalidate: Duration,
+    pub(crate) stale_if_error: Duration,
+}
+
+impl Refreshing {
+    // How long an entry outlives its deadline.
+    pub(crate) fn grace(self) -> Duration {
+        self.stale_while_revalidate.max(self.stale_if_error)
+    }
+
+    // The settings this state was made from, to apply again to a new
+    // deadline.
+    pub(crate) fn settings(self) -> Refresh {
+        Refresh {
+            ahead: self.ahead,
+            stale_while_revalidate: self.stale_while_revalidate,
+            stale_if_error: self.stale_if_error,
+        }
+    }
+}
+
+// An entry's current deadline plus, for sliding entries, how to move it and,
+// for refreshed ones, how to serve it around the deadline.
+#[derive(Clone, Copy)]
+pub(crate) struct Schedule {
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) sliding: Option<Sliding>,
+    pub(crate) refresh: Option<Refreshing>,
+}
+
+impl Schedule {
+    pub(crate) fn with_refresh(mut self, refresh: Refresh, now: Instant) -> Self {
+        let refresh_at = match (self.deadline, refresh.ahead) {
+            (Some(deadline), Some(ahead)) if ahead > 0.0 && ahead < 1.0 => {
+                Some(now + deadline.saturating_duration_since(now).mul_f64(ahead))
+            }
+            _ => None,
+        };
+        self.refresh = Some(Refreshing {
+            refresh_at,
+            ahead: refresh.ahead,
+            stale_while_revalidate: refresh.stale_while_revalidate,
+            stale_if_error: refresh.stale_if_error,
+        });
+        self
+    }
+}
+
+impl Expiry {
+    pub(crate) fn schedule(self, now: Instant) -> Schedule {
+        match self {
+            Expiry::Never => Schedule {
+                deadline: None,
+                sliding: None,
+                refresh: None,
+            },
+            Expiry::After(ttl) => Schedule {
+                deadline: now.checked_add(ttl),
+                sliding: None,
+                refresh: None,
+            },
+            Expiry::Sliding { ttl, max_lifetime } => {
+                let sliding = Sliding {
+                    ttl,
+                    hard_deadline: max_lifetime.and_then(|lifetime| now.checked_add(lifetime)),
+                };
+                Schedule {
+                    deadline: sliding.deadline(now),
+                    sliding: Some(sliding),
+                    refresh: None,
+                }
+            }
+        }
+    }
+}
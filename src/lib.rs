@@ -0,0 +1,36 @@
+//! An in-process key-value cache with expiry, pluggable eviction and
+//! weight-based bounds.
+//!
+//! [`Cache`] is the single-threaded core. [`ShardedCache`] splits keys
+//! over several independently locked caches for use from async code, and
+//! is what the `raw-cache` HTTP server is built on.
+//!
+//! Eviction is chosen per cache through an [`EvictionPolicy`]: [`LruPolicy`]
+//! (the default), [`LfuPolicy`], [`FifoPolicy`], [`RandomPolicy`] or
+//! [`TinyLfuPolicy`].
+//!
+//! Every cache counts its hits, misses, evictions and writes in
+//! [`CacheStats`], and can report each entry it drops to a
+//! [`RemovalListener`]. Deadlines are measured on a [`Clock`], the
+//! system's unless another one is supplied.
+
+#![warn(missing_docs)]
+
+mod cache;
+mod clock;
+mod expiry;
+mod list;
+mod listener;
+mod policy;
+mod sharded;
+mod stats;
+mod weigher;
+
+pub use cache::{Cache, CacheError, Counter, SetCondition, SetOutcome};
+pub use clock::{Clock, SystemClock};
+pub use expiry::{Expiry, Refresh};
+pub use listener::{ChannelListener, Removal, RemovalCause, RemovalListener};
+pub use policy::{EvictionPolicy, FifoPolicy, LfuPolicy, LruPolicy, RandomPolicy, TinyLfuPolicy};
+pub use sharded::ShardedCache;
+pub use stats::CacheStats;
+pub use weigher::{ByteLen, EntryCount, Weigher};
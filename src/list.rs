@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+const NIL: usize = usize::MAX;
+
+struct Node<K> {
+    key: Option<K>,
+    prev: usize,
+    next: usize,
+}
+
+// Doubly linked list of keys stored in a slab, with a key -> slot index so
+// that touching, removing and popping the oldest key are all O(1).
+pub(crate) struct LruList<K> {
+    nodes: Vec<Node<K>>,
+    index: HashMap<K, usize>,
+    free: Vec<usize>,
+    head: usize,
+    tail: usize,
+}
+
+impl<K> LruList<K>
+    where
+        K: Eq + Hash + Clone,
+{
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        LruList {
+            nodes: Vec::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (prev, next) = (self.nodes[slot].prev, self.nodes[slot].next);
+        if prev == NIL {
+            self.head = next;
+        } else {
+            self.nodes[prev].next = next;
+        }
+        if next == NIL {
+            self.tail = prev;
+        } else {
+            self.nodes[next].prev = prev;
+        }
+    }
+
+    fn link_back(&mut self, slot: usize) {
+        self.nodes[slot].prev = self.tail;
+        self.nodes[slot].next = NIL;
+        if self.tail == NIL {
+            self.head = slot;
+        } else {
+            self.nodes[self.tail].next = slot;
+        }
+        self.tail = slot;
+    }
+
+    pub(crate) fn push_back(&mut self, key: K) {
+        if let Some(&slot) = self.index.get(&key) {
+            self.unlink(slot);
+            self.link_back(slot);
+            return;
+        }
+        let node = Node {
+            key: Some(key.clone()),
+            prev: NIL,
+            next: NIL,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = node;
+                slot
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        self.index.insert(key, slot);
+        self.link_back(slot);
+    }
+
+    pub(crate) fn touch(&mut self, key: &K) {
+        if let Some(&slot) = self.index.get(key) {
+            self.unlink(slot);
+            self.link_back(slot);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) -> Option<K> {
+        let slot = self.index.remove(key)?;
+        self.unlink(slot);
+        self.free.push(slot);
+        self.nodes[slot].key.take()
+    }
+
+    pub(crate) fn front(&self) -> Option<&K> {
+        if self.head == NIL {
+            return None;
+        }
+        self.nodes[self.head].key.as_ref()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.head == NIL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keys from head to tail, checking the back links on the way.
+    fn keys(list: &LruList<u32>) -> Vec<u32> {
+        let mut keys = Vec::new();
+        let mut prev = NIL;
+        let mut slot = list.head;
+        while slot != NIL {
+            assert_eq!(list.nodes[slot].prev, prev);
+            keys.push(list.nodes[slot].key.unwrap());
+            prev = slot;
+            slot = list.nodes[slot].next;
+        }
+        assert_eq!(list.tail, prev);
+        keys
+    }
+
+    fn list_of(keys: &[u32]) -> LruList<u32> {
+        let mut list = LruList::with_capacity(keys.len());
+        for &key in keys {
+            list.push_back(key);
+        }
+        list
+    }
+
+    #[test]
+    fn remove_head() {
+        let mut list = list_of(&[1, 2, 3]);
+        assert_eq!(list.remove(&1), Some(1));
+        assert_eq!(keys(&list), [2, 3]);
+        assert_eq!(list.front(), Some(&2));
+    }
+
+    #[test]
+    fn remove_tail() {
+        let mut list = list_of(&[1, 2, 3]);
+        assert_eq!(list.remove(&3), Some(3));
+        assert_eq!(keys(&list), [1, 2]);
+        list.push_back(4);
+        assert_eq!(keys(&list), [1, 2, 4]);
+    }
+
+    #[test]
+    fn remove_only_key() {
+        let mut list = list_of(&[1]);
+        assert_eq!(list.remove(&1), Some(1));
+        assert!(list.is_empty());
+        assert_eq!(list.front(), None);
+        assert_eq!(list.remove(&1), None);
+        list.push_back(2);
+        assert_eq!(keys(&list), [2]);
+    }
+
+    #[test]
+    fn reuses_free_slots() {
+        let mut list = list_of(&[1, 2, 3]);
+        list.remove(&2);
+        list.remove(&1);
+        list.push_back(4);
+        list.push_back(5);
+        assert_eq!(list.nodes.len(), 3);
+        assert!(list.free.is_empty());
+        assert_eq!(keys(&list), [3, 4, 5]);
+        list.push_back(6);
+        assert_eq!(list.nodes.len(), 4);
+    }
+
+    #[test]
+    fn push_back_existing_key_moves_it() {
+        let mut list = list_of(&[1, 2, 3]);
+        list.push_back(1);
+        assert_eq!(keys(&list), [2, 3, 1]);
+        list.push_back(1);
+        assert_eq!(keys(&list), [2, 3, 1]);
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.nodes.len(), 3);
+    }
+
+    #[test]
+    fn touch_moves_to_back() {
+        let mut list = list_of(&[1, 2, 3]);
+        list.touch(&2);
+        assert_eq!(keys(&list), [1, 3, 2]);
+        list.touch(&1);
+        assert_eq!(keys(&list), [3, 2, 1]);
+        list.touch(&9);
+        assert_eq!(keys(&list), [3, 2, 1]);
+    }
+}
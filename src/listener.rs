@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::mpsc;
+
+/// Why an entry left a [`Cache`](crate::Cache).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemovalCause {
+    /// Its deadline, and any window in which it could be served stale,
+    /// passed.
+    Expired,
+    /// The eviction policy dropped it to make room. Carries the entry's
+    /// weight.
+    Evicted(usize),
+    /// A write stored a new value under the same key.
+    Replaced,
+    /// It was deleted.
+    Explicit,
+}
+
+/// Told about every entry that leaves a [`Cache`](crate::Cache), with its
+/// key, value and [`RemovalCause`].
+///
+/// Listeners run inline, while the cache (or its shard) is borrowed, so they
+/// should be quick and must not panic. Use [`ChannelListener`] to handle
+/// removals elsewhere. Any `Fn(&K, &V, RemovalCause)` closure is a listener.
+///
+/// Entries dropped because a shard of a
+/// [`ShardedCache`](crate::ShardedCache) was reset after a panic are not
+/// reported.
+pub trait RemovalListener<K, V>: Send + Sync {
+    /// Called once per removed entry.
+    fn on_removal(&self, key: &K, value: &V, cause: RemovalCause);
+}
+
+impl<K, V, F> RemovalListener<K, V> for F
+    where
+        F: Fn(&K, &V, RemovalCause) + Send + Sync,
+{
+    fn on_removal(&self, key: &K, value: &V, cause: RemovalCause) {
+        self(key, value, cause)
+    }
+}
+
+/// A removed entry, as delivered by a [`ChannelListener`].
+#[derive(Clone, Debug)]
+pub struct Removal<K, V> {
+    /// The entry's key.
+    pub key: K,
+    /// The entry's value.
+    pub value: V,
+    /// Why it was removed.
+    pub cause: RemovalCause,
+}
+
+/// Forwards removals to a bounded tokio channel so they can be handled
+/// asynchronously without ever blocking a cache operation.
+///
+/// When the channel is full or closed, the removal is dropped and counted in
+/// [`dropped`](ChannelListener::dropped).
+pub struct ChannelListener<K, V> {
+    sender: mpsc::Sender<Removal<K, V>>,
+    dropped: AtomicU64,
+}
+
+impl<K, V> ChannelListener<K, V> {
+    /// Creates a listener and the receiving end of its channel, which buffers
+    /// up to `capacity` removals.
+    pub fn new(capacity: usize) -> (Self, mpsc::Receiver<Removal<K, V>>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let listener = ChannelListener {
+            sender,
+            dropped: AtomicU64::new(0),
+        };
+        (listener, receiver)
+    }
+
+    /// How many removals could not be delivered.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<K, V> RemovalListener<K, V> for ChannelListener<K, V>
+    where
+        K: Clone + Send,
+        V: Clone + Send,
+{
+    fn on_removal(&self, key: &K, value: &V, cause: RemovalCause) {
+        let removal = Removal {
+            key: key.clone(),
+            value: value.clone(),
+            cause,
+        };
+        if self.sender.try_send(removal).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
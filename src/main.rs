@@ -1,134 +1,66 @@
-use std::collections::{HashMap, VecDeque};
-use std::hash::Hash;
-use std::time::{Duration, Instant};
-use warp::Filter;
-use std::sync::{Arc, RwLock};
-use serde::Deserialize;
+mod config;
+mod server;
+mod upstream;
 
-struct CacheEntry<V> {
-    value: V,
-    expiration: Instant,
-}
+use std::sync::Arc;
 
-pub struct Cache<K, V> {
-    data: HashMap<K, CacheEntry<V>>,
-    order: VecDeque<K>,
-    max_size: usize,
-}
+use raw_cache::{Cache, Expiry, Refresh, RemovalListener, ShardedCache};
 
-impl<K, V> Cache<K, V>
-    where
-        K: Eq + Hash + Clone,
-{
-    pub fn new(max_size: usize) -> Self {
-        Cache {
-            data: HashMap::new(),
-            order: VecDeque::with_capacity(max_size),
-            max_size,
-        }
-    }
+use crate::config::Config;
+use crate::server::{StoredValue, TtlLimits};
+use crate::upstream::Upstream;
 
-    fn remove_order(&mut self, key: &K) {
-        if let Some(pos) = self.order.iter().position(|k| k == key) {
-            self.order.remove(pos);
+#[tokio::main]
+async fn main() {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("raw-cache: invalid configuration: {}", error);
+            std::process::exit(2);
         }
-    }
-
-    pub fn get(&mut self, key: &K) -> Option<&V> {
-        if self.data.contains_key(key) {
-            let is_expired = {
-                let entry = self.data.get(key).unwrap();
-                entry.expiration <= Instant::now()
-            };
-
-            if is_expired {
-                self.delete(key);
-                return None;
-            } else {
-                self.remove_order(key);
-                self.order.push_back(key.clone());
-            }
+    };
+
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .init();
+    log::info!("starting with {:?}", config);
+
+    let shards = config.shards.unwrap_or_else(|| {
+        let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+        parallelism.min(config.capacity)
+    });
+    let max_bytes = config.max_bytes;
+    let eviction_policy = config.eviction_policy;
+    let log_removal: Arc<dyn RemovalListener<String, StoredValue>> =
+        Arc::new(|key: &String, _: &StoredValue, cause| log::trace!("removed `{}`: {:?}", key, cause));
+    let cache = ShardedCache::new(shards, config.capacity, move |capacity| {
+        let policy = eviction_policy.build(capacity);
+        let cache = Cache::with_policy(capacity, policy)
+            .with_removal_listener(Arc::clone(&log_removal));
+        match max_bytes {
+            Some(max_bytes) => cache.with_max_weight(max_bytes),
+            None => cache,
         }
-        self.data.get(key).map(|entry| &entry.value)
+    });
+    let ttl_limits = TtlLimits {
+        default: config.default_ttl,
+        max: config.max_ttl,
+    };
+    let shared_cache = Arc::new(cache);
+
+    if let Some(interval) = config.sweep_interval {
+        tokio::spawn(server::sweep_expired(Arc::clone(&shared_cache), interval));
     }
 
-
-    pub fn set(&mut self, key: K, value: V, ttl: Duration) {
-        if self.data.len() == self.max_size {
-            if let Some(old_key) = self.order.pop_front() {
-                self.data.remove(&old_key);
-            }
-        }
-        let entry = CacheEntry {
-            value,
-            expiration: Instant::now() + ttl,
+    let upstream = config.upstream_url.as_ref().map(|url| {
+        let expiry = config.default_ttl.map_or(Expiry::Never, Expiry::After);
+        let refresh = Refresh {
+            ahead: config.refresh_ahead,
+            stale_while_revalidate: config.stale_while_revalidate,
+            stale_if_error: config.stale_if_error,
         };
-        self.data.insert(key.clone(), entry);
-        self.order.push_back(key);
-    }
-
-    pub fn delete(&mut self, key: &K) -> Option<V> {
-        self.remove_order(key);
-        self.data.remove(key).map(|entry| entry.value)
-    }
-}
-
-
-
-
-#[derive(Deserialize)]
-struct SetRequestBody {
-    key: String,
-    value: String,
-}
-
-#[derive(Deserialize)]
-struct DeleteRequestBody {
-    key: String,
-}
-
-
-
-#[tokio::main]
-async fn main() {
-    let cache = Cache::new(3);
-    let shared_cache = Arc::new(RwLock::new(cache));
-
-    let set_cache = Arc::clone(&shared_cache);
-    let delete_cache = Arc::clone(&shared_cache);
-
-    let set_route = warp::path("set")
-        .and(warp::any().map(move || Arc::clone(&set_cache)))
-        .and(warp::post())
-        .and(warp::body::json())
-        .and_then(set_handler);
-
-    let delete_route = warp::path("delete")
-        .and(warp::any().map(move || Arc::clone(&delete_cache)))
-        .and(warp::delete())
-        .and(warp::body::json())
-        .and_then(delete_handler);
-
-
-    let routes = set_route.or(delete_route);
-
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-}
-
-async fn set_handler(
-    cache: Arc<RwLock<Cache<String, String>>>,
-    body: SetRequestBody,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut cache = cache.write().unwrap();
-    cache.set(body.key, body.value, Duration::from_secs(5));
-    Ok(warp::reply::json(&"Set successful"))
-}
-
-async fn delete_handler(
-    cache: Arc<RwLock<Cache<String, String>>>,
-    body: DeleteRequestBody,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut cache = cache.write().unwrap();
-    cache.delete(&body.key);
-    Ok(warp::reply::json(&"Delete successful"))
+        Upstream::new(url, expiry, refresh)
+    });
+    let routes = server::routes(&shared_cache, ttl_limits, config.max_batch_size, upstream);
+    warp::serve(routes).run((config.address, config.port)).await;
 }
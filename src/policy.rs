@@ -0,0 +1,701 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::list::LruList;
+
+/// Bookkeeping hooks a [`Cache`](crate::Cache) calls so the policy can pick
+/// which key to evict when the cache is over capacity.
+///
+/// `victim` only chooses a key; the cache then removes it and reports that
+/// through `on_remove`.
+pub trait EvictionPolicy<K> {
+    /// A key was added to the cache.
+    fn on_insert(&mut self, key: &K);
+    /// A live key was read or overwritten.
+    fn on_access(&mut self, key: &K);
+    /// A key left the cache, for whatever reason.
+    fn on_remove(&mut self, key: &K);
+    /// The key to evict next, if any.
+    fn victim(&mut self) -> Option<K>;
+
+    /// Called when a lookup finds nothing, for policies that track the
+    /// popularity of keys they do not hold.
+    fn on_miss(&mut self, _key: &K) {}
+}
+
+impl<K, P> EvictionPolicy<K> for Box<P>
+    where
+        P: EvictionPolicy<K> + ?Sized,
+{
+    fn on_insert(&mut self, key: &K) {
+        (**self).on_insert(key)
+    }
+
+    fn on_access(&mut self, key: &K) {
+        (**self).on_access(key)
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        (**self).on_remove(key)
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        (**self).victim()
+    }
+
+    fn on_miss(&mut self, key: &K) {
+        (**self).on_miss(key)
+    }
+}
+
+/// Evicts the least recently used key.
+pub struct LruPolicy<K> {
+    order: LruList<K>,
+}
+
+impl<K: Eq + Hash + Clone> LruPolicy<K> {
+    /// Creates a policy sized for `capacity` keys.
+    pub fn new(capacity: usize) -> Self {
+        LruPolicy {
+            order: LruList::with_capacity(capacity),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> EvictionPolicy<K> for LruPolicy<K> {
+    fn on_insert(&mut self, key: &K) {
+        self.order.push_back(key.clone());
+    }
+
+    fn on_access(&mut self, key: &K) {
+        self.order.touch(key);
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        self.order.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        self.order.front().cloned()
+    }
+}
+
+/// Evicts the oldest key, ignoring reads.
+pub struct FifoPolicy<K> {
+    order: LruList<K>,
+}
+
+impl<K: Eq + Hash + Clone> FifoPolicy<K> {
+    /// Creates a policy sized for `capacity` keys.
+    pub fn new(capacity: usize) -> Self {
+        FifoPolicy {
+            order: LruList::with_capacity(capacity),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> EvictionPolicy<K> for FifoPolicy<K> {
+    fn on_insert(&mut self, key: &K) {
+        self.order.push_back(key.clone());
+    }
+
+    fn on_access(&mut self, _key: &K) {}
+
+    fn on_remove(&mut self, key: &K) {
+        self.order.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        self.order.front().cloned()
+    }
+}
+
+/// Evicts the least frequently used key, breaking ties by least recent use.
+pub struct LfuPolicy<K> {
+    counts: HashMap<K, u64>,
+    buckets: BTreeMap<u64, LruList<K>>,
+}
+
+impl<K: Eq + Hash + Clone> LfuPolicy<K> {
+    /// Creates a policy sized for `capacity` keys.
+    pub fn new(capacity: usize) -> Self {
+        LfuPolicy {
+            counts: HashMap::with_capacity(capacity),
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    fn unbucket(&mut self, key: &K, count: u64) {
+        if let Some(bucket) = self.buckets.get_mut(&count) {
+            bucket.remove(key);
+            if bucket.is_empty() {
+                self.buckets.remove(&count);
+            }
+        }
+    }
+
+    fn bucket(&mut self, key: &K, count: u64) {
+        self.buckets
+            .entry(count)
+            .or_insert_with(|| LruList::with_capacity(0))
+            .push_back(key.clone());
+    }
+}
+
+impl<K: Eq + Hash + Clone> EvictionPolicy<K> for LfuPolicy<K> {
+    fn on_insert(&mut self, key: &K) {
+        if self.counts.contains_key(key) {
+            return self.on_access(key);
+        }
+        self.counts.insert(key.clone(), 1);
+        self.bucket(key, 1);
+    }
+
+    fn on_access(&mut self, key: &K) {
+        let count = match self.counts.get_mut(key) {
+            Some(count) => count,
+            None => return,
+        };
+        let old = *count;
+        *count = old.saturating_add(1);
+        let new = *count;
+        self.unbucket(key, old);
+        self.bucket(key, new);
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        if let Some(count) = self.counts.remove(key) {
+            self.unbucket(key, count);
+        }
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        let (_, bucket) = self.buckets.first_key_value()?;
+        bucket.front().cloned()
+    }
+}
+
+/// Evicts a key chosen uniformly at random.
+pub struct RandomPolicy<K, R = StdRng> {
+    keys: Vec<K>,
+    index: HashMap<K, usize>,
+    rng: R,
+}
+
+impl<K: Eq + Hash + Clone> RandomPolicy<K> {
+    /// Creates a policy sized for `capacity` keys, seeded from the operating
+    /// system.
+    pub fn new(capacity: usize) -> Self {
+        RandomPolicy::with_rng(capacity, StdRng::from_entropy())
+    }
+}
+
+impl<K: Eq + Hash + Clone, R: Rng> RandomPolicy<K, R> {
+    /// Creates a policy sized for `capacity` keys that picks victims with
+    /// `rng`, e.g. a seeded one for reproducible evictions.
+    pub fn with_rng(capacity: usize, rng: R) -> Self {
+        RandomPolicy {
+            keys: Vec::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+            rng,
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, R: Rng> EvictionPolicy<K> for RandomPolicy<K, R> {
+    fn on_insert(&mut self, key: &K) {
+        if !self.index.contains_key(key) {
+            self.index.insert(key.clone(), self.keys.len());
+            self.keys.push(key.clone());
+        }
+    }
+
+    fn on_access(&mut self, _key: &K) {}
+
+    fn on_remove(&mut self, key: &K) {
+        if let Some(pos) = self.index.remove(key) {
+            self.keys.swap_remove(pos);
+            if let Some(moved) = self.keys.get(pos) {
+                self.index.insert(moved.clone(), pos);
+            }
+        }
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let pos = self.rng.gen_range(0..self.keys.len());
+        Some(self.keys[pos].clone())
+    }
+}
+
+// Count-min sketch of 4-bit-ish counters (saturating at 15) fronted by a
+// doorkeeper bloom filter, so keys seen only once never reach the counters.
+// All counters are halved every `sample_size` additions so old popularity
+// ages out.
+struct FrequencySketch {
+    counters: Vec<u8>,
+    doorkeeper: Vec<u64>,
+    mask: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+const SKETCH_SEEDS: [u64; 4] = [
+    0x9e37_79b9_7f4a_7c15,
+    0xbf58_476d_1ce4_e5b9,
+    0x94d0_49bb_1331_11eb,
+    0xd6e8_feb8_6659_fd93,
+];
+const MAX_FREQUENCY: u8 = 15;
+
+impl FrequencySketch {
+    fn new(capacity: usize) -> Self {
+        let width = capacity.max(16).next_power_of_two();
+        FrequencySketch {
+            counters: vec![0; width * SKETCH_SEEDS.len()],
+            doorkeeper: vec![0; (width * 4 / 64).max(1)],
+            mask: width - 1,
+            additions: 0,
+            sample_size: width * 10,
+        }
+    }
+
+    fn slot(&self, hash: u64, row: usize) -> usize {
+        let mixed = (hash ^ (hash >> 31)).wrapping_mul(SKETCH_SEEDS[row]);
+        row * (self.mask + 1) + ((mixed >> 32) as usize & self.mask)
+    }
+
+    fn doorkeeper_bits(&self, hash: u64) -> [usize; 2] {
+        let bits = self.doorkeeper.len() * 64;
+        [hash as usize % bits, (hash >> 32) as usize % bits]
+    }
+
+    fn in_doorkeeper(&self, hash: u64) -> bool {
+        self.doorkeeper_bits(hash)
+            .iter()
+            .all(|&bit| self.doorkeeper[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn increment(&mut self, hash: u64) {
+        if !self.in_doorkeeper(hash) {
+            for bit in self.doorkeeper_bits(hash) {
+                self.doorkeeper[bit / 64] |= 1 << (bit % 64);
+            }
+        } else {
+            for row in 0..SKETCH_SEEDS.len() {
+                let slot = self.slot(hash, row);
+                if self.counters[slot] < MAX_FREQUENCY {
+                    self.counters[slot] += 1;
+                }
+            }
+        }
+
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            self.reset();
+        }
+    }
+
+    fn frequency(&self, hash: u64) -> u8 {
+        let count = (0..SKETCH_SEEDS.len())
+            .map(|row| self.counters[self.slot(hash, row)])
+            .min()
+            .unwrap_or(0);
+        count + self.in_doorkeeper(hash) as u8
+    }
+
+    fn reset(&mut self) {
+        for counter in &mut self.counters {
+            *counter /= 2;
+        }
+        for word in &mut self.doorkeeper {
+            *word = 0;
+        }
+        self.additions /= 2;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Region {
+    Window,
+    Probation,
+    Protected,
+}
+
+/// Window-TinyLFU admission and eviction.
+///
+/// New keys land in a small LRU window (1% of capacity). When the window
+/// overflows, its oldest key only displaces the oldest key of the main
+/// segmented LRU if a frequency sketch says it is requested more often, so a
+/// one-off scan cannot flush the hot set.
+pub struct TinyLfuPolicy<K> {
+    sketch: FrequencySketch,
+    hasher: RandomState,
+    regions: HashMap<K, Region>,
+    window: LruList<K>,
+    probation: LruList<K>,
+    protected: LruList<K>,
+    window_capacity: usize,
+    protected_capacity: usize,
+}
+
+impl<K: Eq + Hash + Clone> TinyLfuPolicy<K> {
+    /// Creates a policy sized for `capacity` keys.
+    pub fn new(capacity: usize) -> Self {
+        let window_capacity = (capacity / 100).max(1);
+        let main_capacity = capacity.saturating_sub(window_capacity);
+        TinyLfuPolicy {
+            sketch: FrequencySketch::new(capacity),
+            hasher: RandomState::new(),
+            regions: HashMap::with_capacity(capacity),
+            window: LruList::with_capacity(window_capacity),
+            probation: LruList::with_capacity(main_capacity),
+            protected: LruList::with_capacity(main_capacity),
+            window_capacity,
+            protected_capacity: main_capacity * 8 / 10,
+        }
+    }
+
+    fn record(&mut self, key: &K) {
+        let hash = self.hasher.hash_one(key);
+        self.sketch.increment(hash);
+    }
+
+    fn frequency(&self, key: &K) -> u8 {
+        self.sketch.frequency(self.hasher.hash_one(key))
+    }
+}
+
+impl<K: Eq + Hash + Clone> EvictionPolicy<K> for TinyLfuPolicy<K> {
+    fn on_insert(&mut self, key: &K) {
+        if self.regions.contains_key(key) {
+            return self.on_access(key);
+        }
+        self.record(key);
+        self.regions.insert(key.clone(), Region::Window);
+        self.window.push_back(key.clone());
+
+        if self.window.len() > self.window_capacity {
+            if let Some(candidate) = self.window.front().cloned() {
+                self.window.remove(&candidate);
+                self.regions.insert(candidate.clone(), Region::Probation);
+                self.probation.push_back(candidate);
+            }
+        }
+    }
+
+    fn on_access(&mut self, key: &K) {
+        self.record(key);
+        match self.regions.get(key) {
+            Some(Region::Window) => self.window.touch(key),
+            Some(Region::Probation) => {
+                self.probation.remove(key);
+                self.protected.push_back(key.clone());
+                self.regions.insert(key.clone(), Region::Protected);
+                if self.protected.len() > self.protected_capacity {
+                    if let Some(demoted) = self.protected.front().cloned() {
+                        self.protected.remove(&demoted);
+                        self.regions.insert(demoted.clone(), Region::Probation);
+                        self.probation.push_back(demoted);
+                    }
+                }
+            }
+            Some(Region::Protected) => self.protected.touch(key),
+            None => {}
+        }
+    }
+
+    fn on_miss(&mut self, key: &K) {
+        self.record(key);
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        match self.regions.remove(key) {
+            Some(Region::Window) => self.window.remove(key),
+            Some(Region::Probation) => self.probation.remove(key),
+            Some(Region::Protected) => self.protected.remove(key),
+            None => None,
+        };
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        let victim = self
+            .probation
+            .front()
+            .or_else(|| self.protected.front())
+            .cloned();
+        // The window's oldest key is about to be pushed into the main region
+        // by the incoming insert; admit it only if it beats the main victim.
+        let candidate = if self.window.len() >= self.window_capacity {
+            self.window.front().cloned()
+        } else {
+            None
+        };
+
+        match (candidate, victim) {
+            (Some(candidate), Some(victim)) => {
+                if self.frequency(&candidate) > self.frequency(&victim) {
+                    Some(victim)
+                } else {
+                    Some(candidate)
+                }
+            }
+            (candidate, victim) => victim.or(candidate).or_else(|| self.window.front().cloned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cache, Expiry};
+
+    const CAPACITY: usize = 1_000;
+
+    // The traces below are synthetic: seeded generators shaped like the
+    // access patterns being compared, not recordings of real traffic.
+    // `benches/hit_ratio.rs` prints the ratios they give every policy.
+
+    // Half the requests go to a hot set that fits in the cache; the other
+    // half sweep through a key range far larger than it, like the batch jobs
+    // TinyLFU was added for.
+    fn scan_trace() -> Vec<u64> {
+        let mut rng = StdRng::seed_from_u64(8);
+        let mut next_scan_key = 1_000_000;
+        let mut trace = Vec::new();
+        for _ in 0..100 {
+            for _ in 0..1_000 {
+                trace.push(rng.gen_range(0..CAPACITY as u64 / 2));
+            }
+            for _ in 0..1_000 {
+                trace.push(next_scan_key);
+                next_scan_key += 1;
+            }
+        }
+        trace
+    }
+
+    // A loop over slightly more keys than fit, which LRU always misses.
+    fn loop_trace() -> Vec<u64> {
+        (0..100_000).map(|i| i % (CAPACITY as u64 * 5 / 4)).collect()
+    }
+
+    // Popularity falling off steeply with rank, over a hundred times more
+    // keys than fit.
+    fn skewed_trace() -> Vec<u64> {
+        let mut rng = StdRng::seed_from_u64(8);
+        let keys = CAPACITY as f64 * 100.0;
+        (0..100_000)
+            .map(|_| keys.powf(rng.gen::<f64>()) as u64)
+            .collect()
+    }
+
+    // Replays `trace` as a read-through cache would and returns the hit
+    // ratio.
+    fn hit_ratio<P: EvictionPolicy<u64>>(trace: &[u64], policy: P) -> f64 {
+        let mut cache = Cache::with_policy(CAPACITY, policy);
+        for &key in trace {
+            if cache.get(&key).is_none() {
+                cache.set(key, (), Expiry::Never).unwrap();
+            }
+        }
+        cache.stats().hit_ratio()
+    }
+
+    // The hit ratios of LRU and TinyLFU on `trace`.
+    fn compare(trace: &[u64]) -> (f64, f64) {
+        let lru = hit_ratio(trace, LruPolicy::new(CAPACITY));
+        let tiny_lfu = hit_ratio(trace, TinyLfuPolicy::new(CAPACITY));
+        (lru, tiny_lfu)
+    }
+
+    #[test]
+    fn tiny_lfu_resists_scans() {
+        let (lru, tiny_lfu) = compare(&scan_trace());
+        assert!(tiny_lfu > lru + 0.1);
+    }
+
+    #[test]
+    fn tiny_lfu_survives_loops() {
+        let (lru, tiny_lfu) = compare(&loop_trace());
+        assert_eq!(lru, 0.0);
+        assert!(tiny_lfu > 0.5);
+    }
+
+    #[test]
+    fn tiny_lfu_keeps_up_on_skewed_traffic() {
+        let (lru, tiny_lfu) = compare(&skewed_trace());
+        assert!(tiny_lfu >= lru);
+    }
+
+    fn policy_of<P: EvictionPolicy<&'static str>>(mut policy: P, keys: &[&'static str]) -> P {
+        for key in keys {
+            policy.on_insert(key);
+        }
+        policy
+    }
+
+    #[test]
+    fn lfu_evicts_least_frequent_then_least_recent() {
+        let mut lfu = policy_of(LfuPolicy::new(4), &["a", "b", "c"]);
+        lfu.on_access(&"a");
+        assert_eq!(lfu.victim(), Some("b"));
+        lfu.on_access(&"b");
+        assert_eq!(lfu.victim(), Some("c"));
+        lfu.on_access(&"c");
+        // All three were used twice; `a` least recently.
+        assert_eq!(lfu.victim(), Some("a"));
+
+        // Inserting a key that is already tracked counts as a use.
+        lfu.on_insert(&"b");
+        lfu.on_remove(&"a");
+        assert_eq!(lfu.victim(), Some("c"));
+    }
+
+    #[test]
+    fn lfu_drops_empty_buckets() {
+        let mut lfu = policy_of(LfuPolicy::new(4), &["a", "b"]);
+        lfu.on_access(&"a");
+        lfu.on_access(&"a");
+        assert_eq!(lfu.buckets.keys().copied().collect::<Vec<_>>(), [1, 3]);
+
+        lfu.on_remove(&"b");
+        assert_eq!(lfu.buckets.keys().copied().collect::<Vec<_>>(), [3]);
+        assert_eq!(lfu.victim(), Some("a"));
+        lfu.on_remove(&"a");
+        assert!(lfu.buckets.is_empty() && lfu.counts.is_empty());
+        assert_eq!(lfu.victim(), None);
+
+        // Unknown keys are ignored.
+        lfu.on_access(&"z");
+        lfu.on_remove(&"z");
+        assert!(lfu.buckets.is_empty());
+    }
+
+    #[test]
+    fn fifo_ignores_reads() {
+        let mut fifo = policy_of(FifoPolicy::new(4), &["a", "b", "c"]);
+        fifo.on_access(&"a");
+        fifo.on_access(&"a");
+        assert_eq!(fifo.victim(), Some("a"));
+        fifo.on_remove(&"a");
+        assert_eq!(fifo.victim(), Some("b"));
+    }
+
+    // Every tracked key sits at the position its index says.
+    fn assert_indexed<R: Rng>(random: &RandomPolicy<&'static str, R>, keys: &[&str]) {
+        let mut tracked = random.keys.clone();
+        tracked.sort_unstable();
+        assert_eq!(tracked, keys);
+        assert_eq!(random.index.len(), keys.len());
+        for (key, &pos) in &random.index {
+            assert_eq!(random.keys[pos], *key);
+        }
+    }
+
+    #[test]
+    fn random_keeps_its_index_in_step_with_removals() {
+        let mut random = policy_of(RandomPolicy::with_rng(4, StdRng::seed_from_u64(8)), &["a", "b", "c", "d"]);
+        random.on_insert(&"a");
+        assert_indexed(&random, &["a", "b", "c", "d"]);
+
+        // Removing from the middle moves the last key into the hole.
+        random.on_remove(&"b");
+        assert_indexed(&random, &["a", "c", "d"]);
+        random.on_remove(&"d");
+        assert_indexed(&random, &["a", "c"]);
+        random.on_remove(&"z");
+        assert_indexed(&random, &["a", "c"]);
+
+        let mut victims: Vec<_> = (0..100).map(|_| random.victim().unwrap()).collect();
+        victims.sort_unstable();
+        victims.dedup();
+        assert_eq!(victims, ["a", "c"]);
+
+        random.on_remove(&"a");
+        random.on_remove(&"c");
+        assert_indexed(&random, &[]);
+        assert_eq!(random.victim(), None);
+    }
+
+    #[test]
+    fn random_victims_follow_the_seed() {
+        let keys = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        let victims = |seed| {
+            let mut random = policy_of(RandomPolicy::with_rng(8, StdRng::seed_from_u64(seed)), &keys);
+            (0..20).map(|_| random.victim().unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(victims(1), victims(1));
+        assert_ne!(victims(1), victims(2));
+    }
+
+    // Two hashes that share no counter or doorkeeper bit.
+    fn distinct_hashes(sketch: &FrequencySketch) -> (u64, u64) {
+        let (a, b) = (0x0123_4567_89ab_cdef, 0xfedc_ba98_7654_3210);
+        for row in 0..SKETCH_SEEDS.len() {
+            assert_ne!(sketch.slot(a, row), sketch.slot(b, row));
+        }
+        for bit in sketch.doorkeeper_bits(a) {
+            assert!(!sketch.doorkeeper_bits(b).contains(&bit));
+        }
+        (a, b)
+    }
+
+    #[test]
+    fn doorkeeper_absorbs_first_sighting() {
+        let mut sketch = FrequencySketch::new(CAPACITY);
+        let (a, b) = distinct_hashes(&sketch);
+        assert_eq!(sketch.frequency(a), 0);
+        sketch.increment(a);
+        assert_eq!(sketch.frequency(a), 1);
+        assert!(sketch.counters.iter().all(|&counter| counter == 0));
+        sketch.increment(a);
+        assert_eq!(sketch.frequency(a), 2);
+        assert_eq!(sketch.frequency(b), 0);
+    }
+
+    #[test]
+    fn counters_saturate() {
+        let mut sketch = FrequencySketch::new(CAPACITY);
+        let (a, _) = distinct_hashes(&sketch);
+        for _ in 0..100 {
+            sketch.increment(a);
+        }
+        assert_eq!(sketch.frequency(a), MAX_FREQUENCY + 1);
+    }
+
+    #[test]
+    fn reset_halves_counters_and_clears_doorkeeper() {
+        let mut sketch = FrequencySketch::new(CAPACITY);
+        let (a, b) = distinct_hashes(&sketch);
+        for _ in 0..10 {
+            sketch.increment(a);
+        }
+        assert_eq!(sketch.frequency(a), 10);
+
+        // `b` fills up the rest of the sample, which triggers the reset.
+        while sketch.additions < sketch.sample_size - 1 {
+            sketch.increment(b);
+        }
+        assert_eq!(sketch.frequency(b), MAX_FREQUENCY + 1);
+        sketch.increment(b);
+
+        assert_eq!(sketch.additions, sketch.sample_size / 2);
+        assert!(sketch.doorkeeper.iter().all(|&word| word == 0));
+        assert_eq!(sketch.frequency(a), 9 / 2);
+        assert_eq!(sketch.frequency(b), MAX_FREQUENCY / 2);
+
+        // Once the doorkeeper is clear, a key has to be seen again before its
+        // counters grow.
+        sketch.increment(a);
+        assert_eq!(sketch.frequency(a), 9 / 2 + 1);
+        sketch.increment(a);
+        assert_eq!(sketch.frequency(a), 9 / 2 + 2);
+    }
+}
@@ -0,0 +1,1584 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use percent_encoding::percent_decode_str;
+use raw_cache::{CacheError, CacheStats, Counter, EvictionPolicy, Expiry, SetCondition, SetOutcome, ShardedCache};
+use futures_util::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use warp::http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, ETAG};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::{Buf, Filter, Reply};
+
+use crate::upstream::{Upstream, UpstreamError};
+
+#[derive(Clone, Copy)]
+pub struct TtlLimits {
+    pub default: Option<Duration>,
+    pub max: Option<Duration>,
+}
+
+// TTL options shared by the JSON bodies, query strings and headers of the
+// write endpoints. At most one of them may be given.
+#[derive(Deserialize, Default)]
+struct TtlParams {
+    ttl_ms: Option<u64>,
+    ttl_secs: Option<u64>,
+    // Unix timestamp in seconds.
+    expires_at: Option<u64>,
+    #[serde(default)]
+    persist: bool,
+    // Restart the TTL on every read, up to `max_lifetime_secs` in total.
+    #[serde(default)]
+    sliding: bool,
+    max_lifetime_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SetMode {
+    // Only set the key if it does not exist.
+    Nx,
+    // Only set the key if it already exists.
+    Xx,
+}
+
+#[derive(Deserialize)]
+struct SetRequestBody {
+    key: String,
+    value: String,
+    mode: Option<SetMode>,
+    // Compare-and-swap: only write if the entry currently has this version.
+    version: Option<u64>,
+    #[serde(flatten)]
+    ttl: TtlParams,
+}
+
+impl SetRequestBody {
+    fn condition(&self) -> Result<SetCondition, ApiError> {
+        match (self.mode, self.version) {
+            (Some(_), Some(_)) => Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_condition",
+                "mode and version cannot be combined",
+            )
+            .with_key(&self.key)),
+            (Some(SetMode::Nx), None) => Ok(SetCondition::IfAbsent),
+            (Some(SetMode::Xx), None) => Ok(SetCondition::IfPresent),
+            (None, Some(version)) => Ok(SetCondition::IfVersion(version)),
+            (None, None) => Ok(SetCondition::Always),
+        }
+    }
+}
+
+impl TtlParams {
+    fn is_empty(&self) -> bool {
+        self.ttl_ms.is_none()
+            && self.ttl_secs.is_none()
+            && self.expires_at.is_none()
+            && !self.persist
+            && !self.sliding
+            && self.max_lifetime_secs.is_none()
+    }
+
+    fn expiry(&self, limits: TtlLimits) -> Result<Expiry, String> {
+        let given = [
+            self.ttl_ms.is_some(),
+            self.ttl_secs.is_some(),
+            self.expires_at.is_some(),
+            self.persist,
+        ];
+        if given.iter().filter(|&&g| g).count() > 1 {
+            return Err("only one of ttl_ms, ttl_secs, expires_at and persist may be set".to_string());
+        }
+
+        let ttl = if let Some(ms) = self.ttl_ms {
+            Some(Duration::from_millis(ms))
+        } else if let Some(secs) = self.ttl_secs {
+            Some(Duration::from_secs(secs))
+        } else if let Some(at) = self.expires_at {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            match Duration::from_secs(at).checked_sub(now) {
+                Some(ttl) => Some(ttl),
+                None => return Err("expires_at is in the past".to_string()),
+            }
+        } else if self.persist {
+            None
+        } else {
+            limits.default
+        };
+
+        let max_lifetime = self.max_lifetime_secs.map(Duration::from_secs);
+        if max_lifetime.is_some_and(|lifetime| !representable(lifetime)) {
+            return Err("max_lifetime_secs is too large".to_string());
+        }
+        if max_lifetime.is_some() && !self.sliding {
+            return Err("max_lifetime_secs requires sliding".to_string());
+        }
+        if self.sliding && (self.expires_at.is_some() || self.persist) {
+            return Err("sliding needs a relative ttl, not expires_at or persist".to_string());
+        }
+
+        let ttl = match (ttl, limits.max) {
+            (Some(ttl), _) if ttl.is_zero() => return Err("ttl must be greater than zero".to_string()),
+            (Some(ttl), Some(max)) if ttl > max => {
+                return Err(format!("ttl exceeds the maximum of {} seconds", max.as_secs()))
+            }
+            (None, Some(max)) => {
+                return Err(format!("entries must expire within {} seconds", max.as_secs()))
+            }
+            (Some(ttl), _) => ttl,
+            (None, None) if self.sliding => return Err("sliding requires a ttl".to_string()),
+            (None, None) => return Ok(Expiry::Never),
+        };
+        if !representable(ttl) {
+            return Err("ttl is too large".to_string());
+        }
+        if !self.sliding {
+            return Ok(Expiry::After(ttl));
+        }
+        if let (Some(lifetime), Some(max)) = (max_lifetime, limits.max) {
+            if lifetime > max {
+                return Err(format!(
+                    "max_lifetime_secs exceeds the maximum ttl of {} seconds",
+                    max.as_secs()
+                ));
+            }
+        }
+        // Without a cap, reads could keep a sliding entry alive past the
+        // maximum ttl forever.
+        let max_lifetime = max_lifetime.or(limits.max);
+        Ok(Expiry::Sliding { ttl, max_lifetime })
+    }
+}
+
+// Whether a deadline `ttl` from now fits in an `Instant`.
+fn representable(ttl: Duration) -> bool {
+    Instant::now().checked_add(ttl).is_some()
+}
+
+#[derive(Deserialize)]
+struct DeleteRequestBody {
+    key: String,
+}
+
+// What the server stores per key: the raw bytes plus the representation
+// headers they were written with, so reads can return them unchanged.
+#[derive(Clone)]
+pub struct StoredValue {
+    pub body: Bytes,
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+}
+
+const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+
+impl StoredValue {
+    fn text(value: String) -> Self {
+        StoredValue {
+            body: Bytes::from(value),
+            content_type: Some(TEXT_PLAIN.to_string()),
+            content_encoding: None,
+        }
+    }
+
+    fn into_response(self) -> warp::reply::Response {
+        let content_type = self
+            .content_type
+            .as_deref()
+            .unwrap_or("application/octet-stream");
+        let mut response = warp::reply::Response::new(self.body.into());
+        let headers = response.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(content_type) {
+            headers.insert(CONTENT_TYPE, value);
+        }
+        if let Some(encoding) = self.content_encoding.and_then(|e| HeaderValue::from_str(&e).ok()) {
+            headers.insert(CONTENT_ENCODING, encoding);
+        }
+        response
+    }
+}
+
+impl Counter for StoredValue {
+    fn to_counter(&self) -> Option<i64> {
+        std::str::from_utf8(&self.body).ok()?.parse().ok()
+    }
+
+    fn from_counter(value: i64) -> Self {
+        StoredValue::text(value.to_string())
+    }
+}
+
+impl AsRef<[u8]> for StoredValue {
+    fn as_ref(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+#[derive(Serialize)]
+struct GetResponseBody {
+    key: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct SetResponseBody {
+    key: String,
+    created: bool,
+}
+
+#[derive(Deserialize)]
+struct KeysRequestBody {
+    keys: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct MsetItem {
+    key: String,
+    value: String,
+    #[serde(flatten)]
+    ttl: TtlParams,
+}
+
+#[derive(Deserialize)]
+struct MsetRequestBody {
+    items: Vec<MsetItem>,
+}
+
+// One entry per requested key, in request order. Only the fields relevant
+// to the operation are present; failed keys carry `error` instead.
+#[derive(Serialize, Default)]
+struct BatchResult {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    found: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ApiError>,
+}
+
+impl BatchResult {
+    fn failed(key: String, mut error: ApiError) -> Self {
+        error.key = None;
+        BatchResult {
+            key,
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BatchResponseBody {
+    results: Vec<BatchResult>,
+}
+
+#[derive(Deserialize)]
+struct CounterParams {
+    by: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ReadParams {
+    // Look the key up without counting it as a use.
+    #[serde(default)]
+    peek: bool,
+}
+
+#[derive(Serialize)]
+struct CounterResponseBody {
+    key: String,
+    value: i64,
+}
+
+#[derive(Serialize)]
+struct StatsResponseBody {
+    hits: u64,
+    misses: u64,
+    hit_ratio: f64,
+    expired_on_read: u64,
+    evictions: u64,
+    inserts: u64,
+    overwrites: u64,
+    deletes: u64,
+    entries: usize,
+    weight: usize,
+}
+
+impl From<CacheStats> for StatsResponseBody {
+    fn from(stats: CacheStats) -> Self {
+        StatsResponseBody {
+            hits: stats.hits,
+            misses: stats.misses,
+            hit_ratio: stats.hit_ratio(),
+            expired_on_read: stats.expired_on_read,
+            evictions: stats.evictions,
+            inserts: stats.inserts,
+            overwrites: stats.overwrites,
+            deletes: stats.deletes,
+            entries: stats.entries,
+            weight: stats.weight,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TtlResponseBody {
+    key: String,
+    // `null` when the entry never expires.
+    ttl_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct DeleteResponseBody {
+    key: String,
+    found: bool,
+}
+
+// Every error response carries a JSON body of the form
+//
+//     {"code": "not_found", "message": "key not found", "key": "user:1"}
+//
+// `code` is a stable machine-readable identifier, `message` is meant for
+// humans and `key` is omitted when the error is not about a single key.
+#[derive(Clone, Debug, Serialize)]
+struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            key: None,
+        }
+    }
+
+    fn with_key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    fn not_found(key: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", "key not found").with_key(key)
+    }
+
+    fn invalid_ttl(message: impl Into<String>, key: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_ttl", message).with_key(key)
+    }
+
+    fn from_cache(error: CacheError, key: impl Into<String>) -> Self {
+        let (status, code) = match error {
+            CacheError::TooLarge { .. } => (StatusCode::PAYLOAD_TOO_LARGE, "too_large"),
+            CacheError::ZeroCapacity => (StatusCode::INSUFFICIENT_STORAGE, "no_capacity"),
+            CacheError::ConditionFailed { .. } => (StatusCode::PRECONDITION_FAILED, "precondition_failed"),
+            CacheError::NotAnInteger => (StatusCode::UNPROCESSABLE_ENTITY, "not_an_integer"),
+            CacheError::Overflow => (StatusCode::UNPROCESSABLE_ENTITY, "overflow"),
+            CacheError::Panicked => (StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+            CacheError::Load(ref cause) => match cause.downcast_ref::<UpstreamError>() {
+                Some(UpstreamError::NotFound) => return ApiError::not_found(key),
+                _ => (StatusCode::BAD_GATEWAY, "upstream_error"),
+            },
+        };
+        ApiError::new(status, code, error.to_string()).with_key(key)
+    }
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl Reply for ApiError {
+    fn into_response(self) -> warp::reply::Response {
+        let status = self.status;
+        warp::reply::with_status(warp::reply::json(&self), status).into_response()
+    }
+}
+
+async fn handle_rejection(rejection: warp::Rejection) -> Result<warp::reply::Response, Infallible> {
+    let error = if let Some(error) = rejection.find::<ApiError>() {
+        error.clone()
+    } else if rejection.is_not_found() {
+        ApiError::new(StatusCode::NOT_FOUND, "no_route", "no such route")
+    } else if let Some(error) = rejection.find::<warp::reject::InvalidQuery>() {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_query", error.to_string())
+    } else if let Some(error) = rejection.find::<warp::reject::InvalidHeader>() {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_header", error.to_string())
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "method not allowed")
+    } else {
+        log::error!("unhandled rejection: {:?}", rejection);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", "internal server error")
+    };
+    Ok(error.into_response())
+}
+
+// Every endpoint, with errors rendered as JSON by `handle_rejection`. Reads
+// that miss are loaded from `upstream` when one is configured.
+pub fn routes(
+    shared_cache: &SharedCache,
+    ttl_limits: TtlLimits,
+    max_batch_size: usize,
+    upstream: Option<Upstream>,
+) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+    let with_upstream = warp::any().map(move || upstream.clone());
+
+    let set_route = warp::path("set")
+        .and(with_cache(shared_cache))
+        .and(warp::any().map(move || ttl_limits))
+        .and(warp::post())
+        .and(json_body())
+        .and_then(set_handler);
+
+    let delete_route = warp::path("delete")
+        .and(with_cache(shared_cache))
+        .and(warp::delete())
+        .and(json_body())
+        .and_then(delete_handler);
+
+    let get_route = warp::path("get")
+        .and(key_param())
+        .and(warp::path::end())
+        .and(with_cache(shared_cache))
+        .and(with_upstream.clone())
+        .and(warp::get())
+        .and(warp::query::<ReadParams>())
+        .and_then(get_handler);
+
+    let put_key_route = keys_path()
+        .and(warp::path::end())
+        .and(with_cache(shared_cache))
+        .and(warp::any().map(move || ttl_limits))
+        .and(warp::put())
+        .and(ttl_params())
+        .and(write_headers())
+        .and(limited_body())
+        .and_then(put_key_handler);
+
+    let get_key_route = keys_path()
+        .and(warp::path::end())
+        .and(with_cache(shared_cache))
+        .and(with_upstream.clone())
+        .and(warp::get())
+        .and(warp::query::<ReadParams>())
+        .and_then(get_key_handler);
+
+    let head_key_route = keys_path()
+        .and(warp::path::end())
+        .and(with_cache(shared_cache))
+        .and(warp::head())
+        .and_then(head_key_handler);
+
+    let delete_key_route = keys_path()
+        .and(warp::path::end())
+        .and(with_cache(shared_cache))
+        .and(warp::delete())
+        .and_then(delete_key_handler);
+
+    let incr_route = keys_path()
+        .and(warp::path!("incr"))
+        .and(with_cache(shared_cache))
+        .and(warp::any().map(move || ttl_limits))
+        .and(warp::post())
+        .and(warp::query::<CounterParams>())
+        .and(ttl_params())
+        .and_then(|key, cache, limits, params: CounterParams, ttl| {
+            let by = params.by.unwrap_or(1);
+            counter_handler(key, cache, limits, by, false, ttl)
+        });
+
+    let decr_route = keys_path()
+        .and(warp::path!("decr"))
+        .and(with_cache(shared_cache))
+        .and(warp::any().map(move || ttl_limits))
+        .and(warp::post())
+        .and(warp::query::<CounterParams>())
+        .and(ttl_params())
+        .and_then(|key, cache, limits, params: CounterParams, ttl| {
+            let by = params.by.unwrap_or(1);
+            counter_handler(key, cache, limits, by, true, ttl)
+        });
+
+    let get_ttl_route = keys_path()
+        .and(warp::path!("ttl"))
+        .and(with_cache(shared_cache))
+        .and(warp::get())
+        .and_then(get_ttl_handler);
+
+    let expire_route = keys_path()
+        .and(warp::path!("expire"))
+        .and(with_cache(shared_cache))
+        .and(warp::any().map(move || ttl_limits))
+        .and(warp::post())
+        .and(ttl_params())
+        .and_then(expire_handler);
+
+    let persist_route = keys_path()
+        .and(warp::path!("persist"))
+        .and(with_cache(shared_cache))
+        .and(warp::any().map(move || ttl_limits))
+        .and(warp::post())
+        .and_then(persist_handler);
+
+    let touch_route = keys_path()
+        .and(warp::path!("touch"))
+        .and(with_cache(shared_cache))
+        .and(warp::any().map(move || ttl_limits))
+        .and(warp::post())
+        .and(ttl_params())
+        .and_then(touch_handler);
+
+    let mget_route = warp::path("mget")
+        .and(with_cache(shared_cache))
+        .and(warp::post())
+        .and(json_body())
+        .and_then(move |cache, body| mget_handler(cache, max_batch_size, body));
+
+    let mset_route = warp::path("mset")
+        .and(with_cache(shared_cache))
+        .and(warp::any().map(move || ttl_limits))
+        .and(warp::post())
+        .and(json_body())
+        .and_then(move |cache, limits, body| mset_handler(cache, limits, max_batch_size, body));
+
+    let mdel_route = warp::path("mdel")
+        .and(with_cache(shared_cache))
+        .and(warp::post())
+        .and(json_body())
+        .and_then(move |cache, body| mdel_handler(cache, max_batch_size, body));
+
+    let stats_route = warp::path!("stats")
+        .and(with_cache(shared_cache))
+        .and(warp::get())
+        .and_then(stats_handler);
+
+    // A POST rather than a query flag on GET, so that prefetchers, retries
+    // and scrapers cannot zero the counters.
+    let reset_stats_route = warp::path!("stats" / "reset")
+        .and(with_cache(shared_cache))
+        .and(warp::post())
+        .and_then(reset_stats_handler);
+
+    set_route
+        .or(delete_route)
+        .or(get_route)
+        .or(put_key_route)
+        .or(get_key_route)
+        .or(head_key_route)
+        .or(delete_key_route)
+        .or(incr_route)
+        .or(decr_route)
+        .or(get_ttl_route)
+        .or(expire_route)
+        .or(persist_route)
+        .or(touch_route)
+        .or(mget_route)
+        .or(mset_route)
+        .or(mdel_route)
+        .or(stats_route)
+        .or(reset_stats_route)
+        .recover(handle_rejection)
+}
+
+pub const MAX_BODY_BYTES: u64 = 16 * 1024 * 1024;
+
+pub type SharedCache = Arc<ShardedCache<String, StoredValue, Box<dyn EvictionPolicy<String> + Send + Sync>>>;
+
+fn with_cache(cache: &SharedCache) -> impl Filter<Extract = (SharedCache,), Error = Infallible> + Clone {
+    let cache = Arc::clone(cache);
+    warp::any().map(move || Arc::clone(&cache))
+}
+
+// A key taken from one path segment. warp leaves segments percent-encoded,
+// so `/keys/user%201` has to be decoded to find the key `user 1` that the
+// JSON endpoints address as is.
+fn key_param() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::path::param::<String>().and_then(|segment: String| async move {
+        percent_decode_str(&segment)
+            .decode_utf8()
+            .map(|key| key.into_owned())
+            .map_err(|_| {
+                warp::reject::custom(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_key",
+                    "key is not valid UTF-8 once percent-decoded",
+                ))
+            })
+    })
+}
+
+// `/keys/{key}`, followed by the rest of the route.
+fn keys_path() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::path("keys").and(key_param())
+}
+
+// The request body, rejected with 413 as soon as it passes
+// `MAX_BODY_BYTES`. Unlike `warp::body::content_length_limit` this accepts
+// chunked bodies, which carry no `Content-Length`.
+fn limited_body() -> impl Filter<Extract = (Bytes,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and(warp::body::stream())
+        .and_then(collect_body)
+}
+
+async fn collect_body<S, B>(length: Option<u64>, body: S) -> Result<Bytes, warp::Rejection>
+    where
+        S: Stream<Item = Result<B, warp::Error>>,
+        B: Buf,
+{
+    let too_large = || {
+        warp::reject::custom(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "too_large",
+            "request body is too large",
+        ))
+    };
+    if length.is_some_and(|length| length > MAX_BODY_BYTES) {
+        return Err(too_large());
+    }
+    futures_util::pin_mut!(body);
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|error| {
+            warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "invalid_body", error.to_string()))
+        })?;
+        if (bytes.len() + chunk.remaining()) as u64 > MAX_BODY_BYTES {
+            return Err(too_large());
+        }
+        let chunk = chunk.copy_to_bytes(chunk.remaining());
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes.into())
+}
+
+// A JSON request body read through `limited_body`. As with
+// `warp::body::json`, a body without a `Content-Type` is assumed to be JSON.
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and_then(|content_type: Option<String>| async move {
+            let json = content_type.as_deref().is_none_or(|content_type| {
+                let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+                essence == "application/json" || (essence.starts_with("application/") && essence.ends_with("+json"))
+            });
+            if !json {
+                return Err(warp::reject::custom(ApiError::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "unsupported_media_type",
+                    "expected application/json",
+                )));
+            }
+            Ok(())
+        })
+        .untuple_one()
+        .and(limited_body())
+        .and_then(|body: Bytes| async move {
+            serde_json::from_slice(&body).map_err(|error| {
+                warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "invalid_body", error.to_string()))
+            })
+        })
+}
+
+// TTL options for the `/keys` routes, taken from the query string and
+// falling back to `x-ttl-ms`, `x-ttl-secs`, `x-expires-at`, `x-sliding` and
+// `x-max-lifetime-secs` headers.
+fn ttl_params() -> impl Filter<Extract = (TtlParams,), Error = warp::Rejection> + Clone {
+    warp::query::<TtlParams>()
+        .and(warp::header::optional::<u64>("x-ttl-ms"))
+        .and(warp::header::optional::<u64>("x-ttl-secs"))
+        .and(warp::header::optional::<u64>("x-expires-at"))
+        .and(warp::header::optional::<bool>("x-sliding"))
+        .and(warp::header::optional::<u64>("x-max-lifetime-secs"))
+        .map(|mut params: TtlParams, ttl_ms, ttl_secs, expires_at, sliding: Option<bool>, max_lifetime_secs| {
+            params.ttl_ms = params.ttl_ms.or(ttl_ms);
+            params.ttl_secs = params.ttl_secs.or(ttl_secs);
+            params.expires_at = params.expires_at.or(expires_at);
+            params.sliding |= sliding.unwrap_or(false);
+            params.max_lifetime_secs = params.max_lifetime_secs.or(max_lifetime_secs);
+            params
+        })
+}
+
+struct WriteHeaders {
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+}
+
+fn write_headers() -> impl Filter<Extract = (WriteHeaders,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::header::optional::<String>("if-match"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .map(|content_type, content_encoding, if_match, if_none_match| WriteHeaders {
+            content_type,
+            content_encoding,
+            if_match,
+            if_none_match,
+        })
+}
+
+fn set_response(key: String, outcome: SetOutcome<StoredValue>) -> warp::reply::Response {
+    let mut response = if outcome.previous.is_some() {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        let body = SetResponseBody { key, created: true };
+        warp::reply::with_status(warp::reply::json(&body), StatusCode::CREATED).into_response()
+    };
+    insert_etag(&mut response, outcome.version);
+    response
+}
+
+fn insert_etag(response: &mut warp::reply::Response, version: u64) {
+    if let Ok(etag) = HeaderValue::from_str(&format!("\"{}\"", version)) {
+        response.headers_mut().insert(ETAG, etag);
+    }
+}
+
+// Maps `If-Match` / `If-None-Match` on a write to a `SetCondition`. Entity
+// tags are entry versions, as returned in `ETag`.
+fn write_precondition(
+    key: &str,
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+) -> Result<SetCondition, ApiError> {
+    let invalid = |message: &str| {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_header", message).with_key(key)
+    };
+    match (if_match.as_deref().map(str::trim), if_none_match.as_deref().map(str::trim)) {
+        (Some(_), Some(_)) => Err(invalid("If-Match and If-None-Match cannot be combined")),
+        (Some("*"), None) => Ok(SetCondition::IfPresent),
+        // `If-Match` uses strong comparison, which a weak tag never passes.
+        (Some(tag), None) if tag.starts_with("W/") => Err(ApiError::new(
+            StatusCode::PRECONDITION_FAILED,
+            "precondition_failed",
+            "If-Match requires a strong ETag",
+        )
+        .with_key(key)),
+        (Some(tag), None) => tag
+            .trim_matches('"')
+            .parse()
+            .map(SetCondition::IfVersion)
+            .map_err(|_| invalid("If-Match must be `*` or an ETag returned by the server")),
+        (None, Some("*")) => Ok(SetCondition::IfAbsent),
+        (None, Some(_)) => Err(invalid("If-None-Match only supports `*` on writes")),
+        (None, None) => Ok(SetCondition::Always),
+    }
+}
+
+fn insert_ttl_headers(response: &mut warp::reply::Response, ttl: Option<Duration>) {
+    if let Some(ttl) = ttl {
+        let headers = response.headers_mut();
+        headers.insert("x-ttl-ms", (ttl.as_millis() as u64).into());
+        headers.insert("x-ttl-secs", ttl.as_secs().into());
+    }
+}
+
+// Upper bound on entries reclaimed per shard lock acquisition, so a burst of
+// expirations does not stall request handlers.
+const SWEEP_BATCH: usize = 1000;
+
+pub async fn sweep_expired(cache: SharedCache, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let mut reclaimed = 0;
+        loop {
+            let purged = cache.purge_expired(SWEEP_BATCH).await;
+            reclaimed += purged;
+            if purged < SWEEP_BATCH {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        if reclaimed > 0 {
+            log::debug!("expiry sweep reclaimed {} entries", reclaimed);
+        }
+    }
+}
+
+async fn set_handler(
+    cache: SharedCache,
+    limits: TtlLimits,
+    body: SetRequestBody,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let expiry = body
+        .ttl
+        .expiry(limits)
+        .map_err(|error| ApiError::invalid_ttl(error, &body.key))?;
+    let condition = body.condition()?;
+    let key = body.key.clone();
+    let outcome = cache
+        .set_if(body.key, StoredValue::text(body.value), expiry, condition)
+        .await
+        .map_err(|error| ApiError::from_cache(error, &key))?;
+    Ok(set_response(key, outcome))
+}
+
+// Fetches a live entry with its remaining TTL and version. A peek takes
+// only the shard's read lock and leaves recency untouched.
+async fn read_entry(
+    cache: &SharedCache,
+    key: &str,
+    peek: bool,
+    upstream: Option<Upstream>,
+) -> Result<(StoredValue, Option<Duration>, Option<u64>), warp::Rejection> {
+    let key = key.to_string();
+    if let (Some(upstream), false) = (upstream, peek) {
+        return Ok(read_through(cache, key, upstream).await?);
+    }
+    let entry = if peek {
+        cache
+            .with_shard_read(&key, |cache| {
+                let value = cache.peek(&key)?.clone();
+                Some((value, cache.ttl(&key).flatten(), cache.version(&key)))
+            })
+            .await
+    } else {
+        cache
+            .with_shard(&key, |cache| {
+                let value = cache.get(&key)?.clone();
+                Some((value, cache.ttl(&key).flatten(), cache.version(&key)))
+            })
+            .await
+    };
+    let entry = entry
+        .map_err(|error| ApiError::from_cache(error, &key))?
+        .ok_or_else(|| ApiError::not_found(&key))?;
+    Ok(entry)
+}
+
+// Reads `key`, loading it from the upstream if it is missing and refreshing
+// it around its expiry. Loads are shared with concurrent reads of the same
+// key. Stale values and values too large to cache are served without a TTL
+// or version.
+async fn read_through(
+    cache: &SharedCache,
+    key: String,
+    upstream: Upstream,
+) -> Result<(StoredValue, Option<Duration>, Option<u64>), ApiError> {
+    let value = cache
+        .get_with_refresh(key.clone(), upstream.expiry(), upstream.refresh(), |key| async move {
+            upstream.fetch(key).await
+        })
+        .await
+        .map_err(|error| ApiError::from_cache(error, &key))?;
+    let (ttl, version) = cache
+        .with_shard_read(&key, |cache| (cache.ttl(&key).flatten(), cache.version(&key)))
+        .await
+        .map_err(|error| ApiError::from_cache(error, &key))?;
+    Ok((value, ttl, version))
+}
+
+async fn get_handler(
+    key: String,
+    cache: SharedCache,
+    upstream: Option<Upstream>,
+    params: ReadParams,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let (value, ttl, version) = read_entry(&cache, &key, params.peek, upstream).await?;
+    // The JSON API can only carry text; binary values are served by
+    // `/keys/{key}`.
+    let value = String::from_utf8(value.body.to_vec()).map_err(|_| {
+        ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "binary_value",
+            "value is not valid UTF-8, read it from /keys/{key}",
+        )
+        .with_key(&key)
+    })?;
+
+    let mut response = warp::reply::json(&GetResponseBody { key, value }).into_response();
+    insert_ttl_headers(&mut response, ttl);
+    if let Some(version) = version {
+        insert_etag(&mut response, version);
+    }
+    Ok(response)
+}
+
+// Answers 200 either way and says in `found` whether there was anything to
+// delete; `DELETE /keys/{key}` is the one that reports a miss as 404.
+async fn delete_handler(
+    cache: SharedCache,
+    body: DeleteRequestBody,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let found = cache
+        .delete(&body.key)
+        .await
+        .map_err(|error| ApiError::from_cache(error, &body.key))?
+        .is_some();
+    Ok(warp::reply::json(&DeleteResponseBody { key: body.key, found }).into_response())
+}
+
+async fn put_key_handler(
+    key: String,
+    cache: SharedCache,
+    limits: TtlLimits,
+    ttl: TtlParams,
+    headers: WriteHeaders,
+    body: Bytes,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let expiry = ttl
+        .expiry(limits)
+        .map_err(|error| ApiError::invalid_ttl(error, &key))?;
+    let condition = write_precondition(&key, headers.if_match, headers.if_none_match)?;
+    let value = StoredValue {
+        body,
+        content_type: headers.content_type,
+        content_encoding: headers.content_encoding,
+    };
+    let outcome = cache
+        .set_if(key.clone(), value, expiry, condition)
+        .await
+        .map_err(|error| ApiError::from_cache(error, &key))?;
+    Ok(set_response(key, outcome))
+}
+
+async fn get_key_handler(
+    key: String,
+    cache: SharedCache,
+    upstream: Option<Upstream>,
+    params: ReadParams,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let (value, ttl, version) = read_entry(&cache, &key, params.peek, upstream).await?;
+
+    let mut response = value.into_response();
+    insert_ttl_headers(&mut response, ttl);
+    if let Some(version) = version {
+        insert_etag(&mut response, version);
+    }
+    Ok(response)
+}
+
+// Reports existence and remaining TTL without touching recency or sending
+// the value.
+async fn head_key_handler(
+    key: String,
+    cache: SharedCache,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let ttl = cache
+        .ttl(&key)
+        .await
+        .map_err(|error| ApiError::from_cache(error, &key))?
+        .ok_or_else(|| ApiError::not_found(&key))?;
+
+    let mut response = StatusCode::OK.into_response();
+    insert_ttl_headers(&mut response, ttl);
+    Ok(response)
+}
+
+async fn delete_key_handler(
+    key: String,
+    cache: SharedCache,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    cache
+        .delete(&key)
+        .await
+        .map_err(|error| ApiError::from_cache(error, &key))?
+        .ok_or_else(|| ApiError::not_found(&key))?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// Adds `by` to (or subtracts it from) the counter at `key`, creating it with
+// the request's TTL if it does not exist yet.
+async fn counter_handler(
+    key: String,
+    cache: SharedCache,
+    limits: TtlLimits,
+    by: i64,
+    decrement: bool,
+    ttl: TtlParams,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let expiry = ttl
+        .expiry(limits)
+        .map_err(|error| ApiError::invalid_ttl(error, &key))?;
+    let value = if decrement {
+        cache.decr_by(key.clone(), by, expiry).await
+    } else {
+        cache.incr_by(key.clone(), by, expiry).await
+    };
+    let value = value.map_err(|error| ApiError::from_cache(error, &key))?;
+    Ok(warp::reply::json(&CounterResponseBody { key, value }).into_response())
+}
+
+fn check_batch_size(len: usize, max_batch_size: usize) -> Result<(), ApiError> {
+    if len > max_batch_size {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "batch_too_large",
+            format!("batch has {} keys, the limit is {}", len, max_batch_size),
+        ));
+    }
+    Ok(())
+}
+
+fn batch_response(results: Vec<BatchResult>) -> warp::reply::Response {
+    warp::reply::json(&BatchResponseBody { results }).into_response()
+}
+
+async fn mget_handler(
+    cache: SharedCache,
+    max_batch_size: usize,
+    body: KeysRequestBody,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    check_batch_size(body.keys.len(), max_batch_size)?;
+    let found = cache.get_many(&body.keys).await;
+
+    let results = body
+        .keys
+        .into_iter()
+        .zip(found)
+        .map(|(key, found)| match found {
+            Ok(Some(value)) => match String::from_utf8(value.body.to_vec()) {
+                Ok(value) => BatchResult {
+                    key,
+                    found: Some(true),
+                    value: Some(value),
+                    ..Default::default()
+                },
+                Err(_) => BatchResult::failed(
+                    key,
+                    ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "binary_value", "value is not valid UTF-8"),
+                ),
+            },
+            Ok(None) => BatchResult {
+                key,
+                found: Some(false),
+                ..Default::default()
+            },
+            Err(error) => BatchResult::failed(key, ApiError::from_cache(error, "")),
+        })
+        .collect();
+    Ok(batch_response(results))
+}
+
+async fn mset_handler(
+    cache: SharedCache,
+    limits: TtlLimits,
+    max_batch_size: usize,
+    body: MsetRequestBody,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    check_batch_size(body.items.len(), max_batch_size)?;
+
+    // Items with an invalid TTL are answered directly; the rest are written
+    // in one batch.
+    let mut results: Vec<Option<BatchResult>> = Vec::with_capacity(body.items.len());
+    let mut keys = Vec::new();
+    let mut writes = Vec::new();
+    for item in body.items {
+        match item.ttl.expiry(limits) {
+            Ok(expiry) => {
+                results.push(None);
+                keys.push(item.key.clone());
+                writes.push((item.key, StoredValue::text(item.value), expiry));
+            }
+            Err(error) => {
+                let error = ApiError::invalid_ttl(error, "");
+                results.push(Some(BatchResult::failed(item.key, error)));
+            }
+        }
+    }
+
+    let mut written = keys.into_iter().zip(cache.set_many(writes).await);
+    let results = results
+        .into_iter()
+        .map(|result| {
+            result.unwrap_or_else(|| {
+                let (key, written) = written.next().unwrap();
+                match written {
+                    Ok(previous) => BatchResult {
+                        key,
+                        created: Some(previous.is_none()),
+                        ..Default::default()
+                    },
+                    Err(error) => BatchResult::failed(key, ApiError::from_cache(error, "")),
+                }
+            })
+        })
+        .collect();
+    Ok(batch_response(results))
+}
+
+async fn mdel_handler(
+    cache: SharedCache,
+    max_batch_size: usize,
+    body: KeysRequestBody,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    check_batch_size(body.keys.len(), max_batch_size)?;
+    let removed = cache.delete_many(&body.keys).await;
+
+    let results = body
+        .keys
+        .into_iter()
+        .zip(removed)
+        .map(|(key, removed)| match removed {
+            Ok(removed) => BatchResult {
+                key,
+                found: Some(removed.is_some()),
+                ..Default::default()
+            },
+            Err(error) => BatchResult::failed(key, ApiError::from_cache(error, "")),
+        })
+        .collect();
+    Ok(batch_response(results))
+}
+
+async fn stats_handler(cache: SharedCache) -> Result<warp::reply::Response, warp::Rejection> {
+    let stats = cache.stats().await;
+    Ok(warp::reply::json(&StatsResponseBody::from(stats)).into_response())
+}
+
+// Responds with the counters as they were just before they were zeroed.
+async fn reset_stats_handler(cache: SharedCache) -> Result<warp::reply::Response, warp::Rejection> {
+    let stats = cache.reset_stats().await;
+    Ok(warp::reply::json(&StatsResponseBody::from(stats)).into_response())
+}
+
+async fn ttl_response(cache: &SharedCache, key: String) -> Result<warp::reply::Response, warp::Rejection> {
+    let ttl = cache
+        .ttl(&key)
+        .await
+        .map_err(|error| ApiError::from_cache(error, &key))?
+        .ok_or_else(|| ApiError::not_found(&key))?;
+    let ttl_ms = ttl.map(|ttl| ttl.as_millis() as u64);
+    let mut response = warp::reply::json(&TtlResponseBody { key, ttl_ms }).into_response();
+    insert_ttl_headers(&mut response, ttl);
+    Ok(response)
+}
+
+async fn get_ttl_handler(key: String, cache: SharedCache) -> Result<warp::reply::Response, warp::Rejection> {
+    ttl_response(&cache, key).await
+}
+
+async fn expire_handler(
+    key: String,
+    cache: SharedCache,
+    limits: TtlLimits,
+    ttl: TtlParams,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if ttl.is_empty() {
+        return Err(ApiError::invalid_ttl("one of ttl_ms, ttl_secs, expires_at or persist is required", &key).into());
+    }
+    let expiry = ttl
+        .expiry(limits)
+        .map_err(|error| ApiError::invalid_ttl(error, &key))?;
+    let found = cache
+        .expire(&key, expiry)
+        .await
+        .map_err(|error| ApiError::from_cache(error, &key))?;
+    if !found {
+        return Err(ApiError::not_found(key).into());
+    }
+    ttl_response(&cache, key).await
+}
+
+async fn persist_handler(
+    key: String,
+    cache: SharedCache,
+    limits: TtlLimits,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let ttl = TtlParams {
+        persist: true,
+        ..Default::default()
+    };
+    // Rejected when the server enforces a maximum TTL.
+    ttl.expiry(limits)
+        .map_err(|error| ApiError::invalid_ttl(error, &key))?;
+    let found = cache
+        .persist(&key)
+        .await
+        .map_err(|error| ApiError::from_cache(error, &key))?;
+    if !found {
+        return Err(ApiError::not_found(key).into());
+    }
+    ttl_response(&cache, key).await
+}
+
+async fn touch_handler(
+    key: String,
+    cache: SharedCache,
+    limits: TtlLimits,
+    ttl: TtlParams,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let expiry = if ttl.is_empty() {
+        None
+    } else {
+        Some(
+            ttl.expiry(limits)
+                .map_err(|error| ApiError::invalid_ttl(error, &key))?,
+        )
+    };
+    let found = cache
+        .touch(&key, expiry)
+        .await
+        .map_err(|error| ApiError::from_cache(error, &key))?;
+    if !found {
+        return Err(ApiError::not_found(key).into());
+    }
+    ttl_response(&cache, key).await
+}
+
+#[cfg(test)]
+mod tests {
+    use raw_cache::{Cache, LruPolicy};
+
+    use super::*;
+
+    const UNLIMITED: TtlLimits = TtlLimits { default: None, max: None };
+    const MAX_MINUTE: TtlLimits = TtlLimits {
+        default: None,
+        max: Some(Duration::from_secs(60)),
+    };
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn unix_now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn ttl_options_are_mutually_exclusive() {
+        let both = TtlParams {
+            ttl_ms: Some(1),
+            ttl_secs: Some(1),
+            ..Default::default()
+        };
+        assert!(both.expiry(UNLIMITED).is_err());
+        let persist = TtlParams {
+            expires_at: Some(unix_now() + 60),
+            persist: true,
+            ..Default::default()
+        };
+        assert!(persist.expiry(UNLIMITED).is_err());
+    }
+
+    #[test]
+    fn ttls_become_relative_expiries() {
+        let ms = TtlParams {
+            ttl_ms: Some(1500),
+            ..Default::default()
+        };
+        assert_eq!(ms.expiry(UNLIMITED), Ok(Expiry::After(Duration::from_millis(1500))));
+        let whole_secs = TtlParams {
+            ttl_secs: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(whole_secs.expiry(UNLIMITED), Ok(Expiry::After(secs(2))));
+
+        let at = TtlParams {
+            expires_at: Some(unix_now() + 60),
+            ..Default::default()
+        };
+        match at.expiry(UNLIMITED) {
+            Ok(Expiry::After(ttl)) => assert!(ttl > secs(58) && ttl <= secs(60)),
+            other => panic!("expected a relative expiry, got {:?}", other),
+        }
+        let past = TtlParams {
+            expires_at: Some(unix_now() - 1),
+            ..Default::default()
+        };
+        assert_eq!(past.expiry(UNLIMITED), Err("expires_at is in the past".to_string()));
+    }
+
+    #[test]
+    fn zero_ttls_are_rejected() {
+        let zero = TtlParams {
+            ttl_ms: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(zero.expiry(UNLIMITED), Err("ttl must be greater than zero".to_string()));
+    }
+
+    #[test]
+    fn missing_ttls_fall_back_to_the_default() {
+        let limits = TtlLimits {
+            default: Some(secs(30)),
+            ..UNLIMITED
+        };
+        assert_eq!(TtlParams::default().expiry(limits), Ok(Expiry::After(secs(30))));
+        assert_eq!(TtlParams::default().expiry(UNLIMITED), Ok(Expiry::Never));
+
+        let persist = TtlParams {
+            persist: true,
+            ..Default::default()
+        };
+        assert_eq!(persist.expiry(limits), Ok(Expiry::Never));
+    }
+
+    #[test]
+    fn the_max_ttl_bounds_every_entry() {
+        let at_max = TtlParams {
+            ttl_secs: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(at_max.expiry(MAX_MINUTE), Ok(Expiry::After(secs(60))));
+        let over_max = TtlParams {
+            ttl_secs: Some(61),
+            ..Default::default()
+        };
+        assert_eq!(
+            over_max.expiry(MAX_MINUTE),
+            Err("ttl exceeds the maximum of 60 seconds".to_string())
+        );
+
+        let persist = TtlParams {
+            persist: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            persist.expiry(MAX_MINUTE),
+            Err("entries must expire within 60 seconds".to_string())
+        );
+        assert!(TtlParams::default().expiry(MAX_MINUTE).is_err());
+    }
+
+    #[test]
+    fn sliding_needs_a_relative_ttl() {
+        let no_ttl = TtlParams {
+            sliding: true,
+            ..Default::default()
+        };
+        assert_eq!(no_ttl.expiry(UNLIMITED), Err("sliding requires a ttl".to_string()));
+        let absolute = TtlParams {
+            expires_at: Some(unix_now() + 60),
+            ..no_ttl
+        };
+        assert!(absolute.expiry(UNLIMITED).is_err());
+        let lifetime_alone = TtlParams {
+            ttl_secs: Some(10),
+            max_lifetime_secs: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(
+            lifetime_alone.expiry(UNLIMITED),
+            Err("max_lifetime_secs requires sliding".to_string())
+        );
+    }
+
+    #[test]
+    fn unrepresentable_ttls_are_rejected() {
+        let huge = TtlParams {
+            ttl_secs: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert_eq!(huge.expiry(UNLIMITED), Err("ttl is too large".to_string()));
+    }
+
+    #[test]
+    fn sliding_entries_cannot_outlive_the_max_ttl() {
+        let ttl = TtlParams {
+            ttl_secs: Some(10),
+            sliding: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            ttl.expiry(MAX_MINUTE),
+            Ok(Expiry::Sliding { ttl: secs(10), max_lifetime: Some(secs(60)) })
+        );
+        assert_eq!(
+            ttl.expiry(UNLIMITED),
+            Ok(Expiry::Sliding { ttl: secs(10), max_lifetime: None })
+        );
+
+        let capped = TtlParams {
+            max_lifetime_secs: Some(30),
+            ..ttl
+        };
+        assert_eq!(
+            capped.expiry(MAX_MINUTE),
+            Ok(Expiry::Sliding { ttl: secs(10), max_lifetime: Some(secs(30)) })
+        );
+        let too_long = TtlParams {
+            max_lifetime_secs: Some(61),
+            ..capped
+        };
+        assert!(too_long.expiry(MAX_MINUTE).is_err());
+    }
+
+    // The status a rejection is rendered with.
+    async fn rejected_with(rejection: warp::Rejection) -> StatusCode {
+        handle_rejection(rejection).await.unwrap().status()
+    }
+
+    fn chunks(sizes: &[usize]) -> impl Stream<Item = Result<Bytes, warp::Error>> {
+        let chunks: Vec<_> = sizes.iter().map(|&size| Ok(Bytes::from(vec![b'x'; size]))).collect();
+        futures_util::stream::iter(chunks)
+    }
+
+    #[tokio::test]
+    async fn bodies_without_a_length_are_collected() {
+        let body = collect_body(None, chunks(&[3, 0, 4])).await.unwrap();
+        assert_eq!(body, "xxxxxxx");
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_limit_are_rejected_with_or_without_a_length() {
+        let half = MAX_BODY_BYTES as usize / 2;
+        assert_eq!(collect_body(None, chunks(&[half, half])).await.unwrap().len(), 2 * half);
+
+        let rejection = collect_body(None, chunks(&[half, half, 1])).await.unwrap_err();
+        assert_eq!(rejected_with(rejection).await, StatusCode::PAYLOAD_TOO_LARGE);
+        let rejection = collect_body(Some(MAX_BODY_BYTES + 1), chunks(&[1])).await.unwrap_err();
+        assert_eq!(rejected_with(rejection).await, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    fn test_routes() -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+        let cache: SharedCache = Arc::new(ShardedCache::new(1, 100, |capacity| {
+            let policy: Box<dyn EvictionPolicy<String> + Send + Sync> = Box::new(LruPolicy::new(capacity));
+            Cache::with_policy(capacity, policy)
+        }));
+        routes(&cache, UNLIMITED, 100, None)
+    }
+
+    // `PUT /keys/{key}` with `headers`.
+    async fn put(
+        routes: &(impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone + 'static),
+        key: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> warp::http::Response<Bytes> {
+        let mut request = warp::test::request().method("PUT").path(&format!("/keys/{}", key));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(body).reply(routes).await
+    }
+
+    fn etag(response: &warp::http::Response<Bytes>) -> String {
+        response.headers()[ETAG].to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn if_match_any_writes_only_existing_keys() {
+        let routes = test_routes();
+        let missing = put(&routes, "k", &[("if-match", "*")], b"v1").await;
+        assert_eq!(missing.status(), StatusCode::PRECONDITION_FAILED);
+
+        assert_eq!(put(&routes, "k", &[], b"v1").await.status(), StatusCode::CREATED);
+        assert_eq!(put(&routes, "k", &[("if-match", "*")], b"v2").await.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn if_none_match_any_writes_only_missing_keys() {
+        let routes = test_routes();
+        assert_eq!(put(&routes, "k", &[("if-none-match", "*")], b"v1").await.status(), StatusCode::CREATED);
+        let existing = put(&routes, "k", &[("if-none-match", "*")], b"v2").await;
+        assert_eq!(existing.status(), StatusCode::PRECONDITION_FAILED);
+
+        let other_tag = put(&routes, "k", &[("if-none-match", "\"1\"")], b"v2").await;
+        assert_eq!(other_tag.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn if_match_compares_strong_etags() {
+        let routes = test_routes();
+        let created = put(&routes, "k", &[], b"v1").await;
+        let first = etag(&created);
+
+        let updated = put(&routes, "k", &[("if-match", &first)], b"v2").await;
+        assert_eq!(updated.status(), StatusCode::NO_CONTENT);
+        assert_ne!(etag(&updated), first);
+
+        let stale = put(&routes, "k", &[("if-match", &first)], b"v3").await;
+        assert_eq!(stale.status(), StatusCode::PRECONDITION_FAILED);
+        let weak = format!("W/{}", etag(&updated));
+        assert_eq!(put(&routes, "k", &[("if-match", &weak)], b"v3").await.status(), StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[tokio::test]
+    async fn malformed_preconditions_are_bad_requests() {
+        let routes = test_routes();
+        put(&routes, "k", &[], b"v1").await;
+        for headers in [
+            &[("if-match", "\"abc\"")][..],
+            &[("if-match", "*"), ("if-none-match", "*")][..],
+        ] {
+            let response = put(&routes, "k", headers, b"v2").await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    async fn get(
+        routes: &(impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone + 'static),
+        path: &str,
+    ) -> warp::http::Response<Bytes> {
+        warp::test::request().path(path).reply(routes).await
+    }
+
+    #[tokio::test]
+    async fn binary_values_round_trip_byte_for_byte() {
+        let routes = test_routes();
+        let body = [0xff, 0x00, 0xfe, b'g', b'z', 0x80];
+        let headers = [("content-type", "image/x-test"), ("content-encoding", "gzip")];
+        put(&routes, "blob", &headers, &body).await;
+
+        let response = get(&routes, "/keys/blob").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), body);
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/x-test");
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+
+        let untyped = put(&routes, "raw", &[], &body).await;
+        assert_eq!(untyped.status(), StatusCode::CREATED);
+        let response = get(&routes, "/keys/raw").await;
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/octet-stream");
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn the_json_api_refuses_binary_values() {
+        let routes = test_routes();
+        put(&routes, "blob", &[], &[0xff, 0xfe]).await;
+        let response = get(&routes, "/get/blob").await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let error: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(error["code"], "binary_value");
+        assert_eq!(error["key"], "blob");
+
+        put(&routes, "text", &[], "caf\u{e9}".as_bytes()).await;
+        let response = get(&routes, "/get/text").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["value"], "caf\u{e9}");
+    }
+
+    #[tokio::test]
+    async fn writes_answer_created_or_no_content_with_an_etag() {
+        let routes = test_routes();
+        let created = put(&routes, "k", &[], b"v1").await;
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let body: serde_json::Value = serde_json::from_slice(created.body()).unwrap();
+        assert_eq!(body["created"], true);
+
+        let replaced = put(&routes, "k", &[], b"v2").await;
+        assert_eq!(replaced.status(), StatusCode::NO_CONTENT);
+        assert!(replaced.body().is_empty());
+        assert_ne!(etag(&replaced), etag(&created));
+
+        let read = get(&routes, "/keys/k").await;
+        assert_eq!(etag(&read), etag(&replaced));
+        assert_eq!(get(&routes, "/get/k").await.headers()[ETAG], etag(&replaced).as_str());
+
+        let set = warp::test::request()
+            .method("POST")
+            .path("/set")
+            .json(&serde_json::json!({"key": "j", "value": "v"}))
+            .reply(&routes)
+            .await;
+        assert_eq!(set.status(), StatusCode::CREATED);
+        assert!(set.headers().contains_key(ETAG));
+    }
+}
@@ -0,0 +1,805 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use tokio::sync::{OnceCell, RwLock};
+
+use crate::cache::{Cache, CacheError, Counter, Lookup, SetCondition, SetOutcome};
+use crate::expiry::{Expiry, Refresh};
+use crate::policy::{EvictionPolicy, LruPolicy};
+use crate::stats::CacheStats;
+
+/// Spreads keys over independently locked [`Cache`] shards so requests for
+/// different keys do not contend on one lock.
+///
+/// Capacity and weight limits apply to all shards together. A write that
+/// takes the cache over either one evicts from the other shards in turn, so
+/// the entries dropped are only approximately the ones the policy would pick
+/// in a single unsharded cache.
+///
+/// Shards are guarded by async read-write locks so waiting for one never
+/// blocks a runtime thread, and read-only lookups ([`peek`](Self::peek),
+/// [`contains`](Self::contains), [`ttl`](Self::ttl)) can run side by side.
+/// Cache operations never await while holding the lock, and if a write
+/// panics the shard is rebuilt empty and the caller gets
+/// [`CacheError::Panicked`], so a bad request cannot wedge later ones.
+pub struct ShardedCache<K, V, P = LruPolicy<K>> {
+    shards: Vec<RwLock<Cache<K, V, P>>>,
+    hasher: RandomState,
+    build: Box<dyn Fn() -> Cache<K, V, P> + Send + Sync>,
+    capacity: usize,
+    max_weight: Option<usize>,
+    // Entries and weight across all shards, kept in step by `run`.
+    len: AtomicUsize,
+    weight: AtomicUsize,
+    // Loads started by `get_with` that have not finished yet, so concurrent
+    // misses for a key can wait on the same one.
+    inflight: Mutex<HashMap<K, Arc<Flight<V>>>>,
+}
+
+type Flight<V> = OnceCell<Result<V, CacheError>>;
+
+// One caller's share of a flight. Dropping it, whether the load finished or
+// the caller's future was dropped part way, takes the flight out of
+// `inflight` once it is done or nobody else is left waiting on it.
+struct FlightGuard<'a, K: Eq + Hash, V> {
+    inflight: &'a Mutex<HashMap<K, Arc<Flight<V>>>>,
+    key: K,
+    flight: Arc<Flight<V>>,
+}
+
+impl<K: Eq + Hash, V> Drop for FlightGuard<'_, K, V> {
+    fn drop(&mut self) {
+        let mut inflight = self.inflight.lock().unwrap_or_else(PoisonError::into_inner);
+        let current = inflight
+            .get(&self.key)
+            .is_some_and(|current| Arc::ptr_eq(current, &self.flight));
+        // The map's reference and ours are the only ones left.
+        let abandoned = Arc::strong_count(&self.flight) == 2;
+        if current && (self.flight.initialized() || abandoned) {
+            inflight.remove(&self.key);
+        }
+    }
+}
+
+impl<K, V, P> ShardedCache<K, V, P>
+    where
+        K: Eq + Hash + Clone,
+        P: EvictionPolicy<K>,
+{
+    /// Creates `shards` shards (at least one) holding at most `capacity`
+    /// entries between them.
+    ///
+    /// `build` is called once per shard, and again whenever a shard has to be
+    /// reset, with that shard's expected share of `capacity` to size its
+    /// policy by. The entry limit of the caches it returns is raised to
+    /// `capacity`, and their weight limit, if any, is the budget of all
+    /// shards together.
+    pub fn new<F>(shards: usize, capacity: usize, build: F) -> Self
+        where
+            F: Fn(usize) -> Cache<K, V, P> + Send + Sync + 'static,
+    {
+        let shard_capacity = capacity.div_ceil(shards.max(1));
+        let build = move || build(shard_capacity).with_max_size(capacity);
+        let shards: Vec<_> = (0..shards.max(1)).map(|_| build()).collect();
+        ShardedCache {
+            max_weight: shards[0].max_weight(),
+            shards: shards.into_iter().map(RwLock::new).collect(),
+            hasher: RandomState::new(),
+            build: Box::new(build),
+            capacity,
+            len: AtomicUsize::new(0),
+            weight: AtomicUsize::new(0),
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn shard_index(&self, key: &K) -> usize {
+        self.hasher.hash_one(key) as usize % self.shards.len()
+    }
+
+    async fn run<R>(&self, index: usize, f: impl FnOnce(&mut Cache<K, V, P>) -> R) -> Result<R, CacheError> {
+        let result = self.run_shard(index, f).await;
+        self.enforce_bounds(index).await;
+        result
+    }
+
+    async fn run_shard<R>(&self, index: usize, f: impl FnOnce(&mut Cache<K, V, P>) -> R) -> Result<R, CacheError> {
+        let mut shard = self.shards[index].write().await;
+        let (len, weight) = (shard.len(), shard.weight());
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| f(&mut shard))) {
+            Ok(result) => Ok(result),
+            Err(_) => {
+                log::error!("cache operation panicked, resetting shard {}", index);
+                *shard = (self.build)();
+                Err(CacheError::Panicked)
+            }
+        };
+        adjust(&self.len, len, shard.len());
+        adjust(&self.weight, weight, shard.weight());
+        result
+    }
+
+    fn over_budget(&self) -> bool {
+        self.len.load(Ordering::Relaxed) > self.capacity
+            || self
+                .max_weight
+                .is_some_and(|max_weight| self.weight.load(Ordering::Relaxed) > max_weight)
+    }
+
+    // Shards only keep themselves within budget, so after a write to shard
+    // `written` the cache as a whole may be over. Evicts from the shards after
+    // it, one lock at a time, and from `written` itself last so the entry
+    // just stored is not dropped for being the newest in a near-empty shard.
+    async fn enforce_bounds(&self, written: usize) {
+        for offset in 1..=self.shards.len() {
+            if !self.over_budget() {
+                return;
+            }
+            let index = (written + offset) % self.shards.len();
+            let _ = self
+                .run_shard(index, |cache| {
+                    let extra_len = self.len.load(Ordering::Relaxed).saturating_sub(self.capacity);
+                    let extra_weight = self
+                        .max_weight
+                        .map_or(0, |max_weight| self.weight.load(Ordering::Relaxed).saturating_sub(max_weight));
+                    cache.evict_down_to(
+                        cache.len().saturating_sub(extra_len),
+                        cache.weight().saturating_sub(extra_weight),
+                    );
+                })
+                .await;
+        }
+    }
+
+    // A read-only operation cannot leave the shard half-updated, so a panic
+    // here is reported without resetting anything.
+    async fn run_read<R>(&self, index: usize, f: impl FnOnce(&Cache<K, V, P>) -> R) -> Result<R, CacheError> {
+        let shard = self.shards[index].read().await;
+        panic::catch_unwind(AssertUnwindSafe(|| f(&shard))).map_err(|_| {
+            log::error!("cache read panicked on shard {}", index);
+            CacheError::Panicked
+        })
+    }
+
+    /// Runs `f` against the shard owning `key` while holding its lock, for
+    /// operations that need several cache calls to be atomic.
+    pub async fn with_shard<R>(&self, key: &K, f: impl FnOnce(&mut Cache<K, V, P>) -> R) -> Result<R, CacheError> {
+        self.run(self.shard_index(key), f).await
+    }
+
+    /// Like [`with_shard`](Self::with_shard), but under a shared lock for
+    /// `&self` cache methods.
+    pub async fn with_shard_read<R>(&self, key: &K, f: impl FnOnce(&Cache<K, V, P>) -> R) -> Result<R, CacheError> {
+        self.run_read(self.shard_index(key), f).await
+    }
+
+    /// [`Cache::get`] on the owning shard.
+    pub async fn get(&self, key: &K) -> Result<Option<V>, CacheError>
+        where
+            V: Clone,
+    {
+        self.with_shard(key, |cache| cache.get(key).cloned()).await
+    }
+
+    /// Returns the live value for `key`, or loads it with `loader` and
+    /// stores it with `expiry`.
+    ///
+    /// Concurrent misses for the same key share a single call to `loader`:
+    /// the first caller runs it and the rest wait for its result. A failed
+    /// load is reported to all of them as [`CacheError::Load`] and is not
+    /// cached, so the next call tries again. As with [`Cache::get_with`], the
+    /// loaded value is returned even if the cache cannot hold it.
+    pub async fn get_with<F, Fut, E>(&self, key: K, expiry: Expiry, loader: F) -> Result<V, CacheError>
+        where
+            V: Clone,
+            F: FnOnce(K) -> Fut,
+            Fut: Future<Output = Result<V, E>>,
+            E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        if let Some(value) = self.get(&key).await? {
+            return Ok(value);
+        }
+        self.load(key, expiry, None, loader).await
+    }
+
+    /// Like [`get_with`](Self::get_with), but keeps the entry available
+    /// around its expiry as described by `refresh`.
+    ///
+    /// Once the refresh-ahead point has passed, or within the
+    /// stale-while-revalidate window after expiry, the current value is
+    /// returned right away and `loader` runs in a background task. Within
+    /// the stale-if-error window `loader` runs in the foreground, and the old
+    /// value is returned if it fails. Background loads share the single
+    /// flight of foreground ones.
+    pub async fn get_with_refresh<F, Fut, E>(
+        self: &Arc<Self>,
+        key: K,
+        expiry: Expiry,
+        refresh: Refresh,
+        loader: F,
+    ) -> Result<V, CacheError>
+        where
+            K: Send + Sync + 'static,
+            V: Clone + Send + Sync + 'static,
+            P: Send + Sync + 'static,
+            F: FnOnce(K) -> Fut + Send + 'static,
+            Fut: Future<Output = Result<V, E>> + Send + 'static,
+            E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        match self.with_shard(&key, |cache| cache.lookup(&key)).await? {
+            Lookup::Fresh(value) => Ok(value),
+            Lookup::RefreshDue(value) | Lookup::StaleWhileRevalidate(value) => {
+                self.spawn_refresh(key, expiry, refresh, loader);
+                Ok(value)
+            }
+            Lookup::StaleIfError(stale) => match self.load(key, expiry, Some(refresh), loader).await {
+                Err(CacheError::Load(error)) => {
+                    log::warn!("serving stale value after a failed reload: {}", error);
+                    Ok(stale)
+                }
+                result => result,
+            },
+            Lookup::Miss => self.load(key, expiry, Some(refresh), loader).await,
+        }
+    }
+
+    fn spawn_refresh<F, Fut, E>(self: &Arc<Self>, key: K, expiry: Expiry, refresh: Refresh, loader: F)
+        where
+            K: Send + Sync + 'static,
+            V: Clone + Send + Sync + 'static,
+            P: Send + Sync + 'static,
+            F: FnOnce(K) -> Fut + Send + 'static,
+            Fut: Future<Output = Result<V, E>> + Send + 'static,
+            E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        if self.inflight.lock().unwrap().contains_key(&key) {
+            return;
+        }
+        let cache = Arc::clone(self);
+        tokio::spawn(async move {
+            if let Err(error) = cache.load(key, expiry, Some(refresh), loader).await {
+                log::warn!("background refresh failed: {}", error);
+            }
+        });
+    }
+
+    // Runs `loader` for `key` unless a load for it is already in flight, in
+    // which case this waits for that one instead.
+    async fn load<F, Fut, E>(&self, key: K, expiry: Expiry, refresh: Option<Refresh>, loader: F) -> Result<V, CacheError>
+        where
+            V: Clone,
+            F: FnOnce(K) -> Fut,
+            Fut: Future<Output = Result<V, E>>,
+            E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        let flight = {
+            let mut inflight = self.inflight.lock().unwrap();
+            Arc::clone(inflight.entry(key.clone()).or_default())
+        };
+        let load_key = key.clone();
+        let guard = FlightGuard {
+            inflight: &self.inflight,
+            key,
+            flight,
+        };
+        guard
+            .flight
+            .get_or_init(|| async move {
+                // A load that finished after our lookup has already filled
+                // the cache.
+                let fresh = self
+                    .with_shard_read(&load_key, |cache| cache.peek_fresh(&load_key).cloned())
+                    .await?;
+                if let Some(value) = fresh {
+                    return Ok(value);
+                }
+                let value = loader(load_key.clone())
+                    .await
+                    .map_err(|error| CacheError::Load(Arc::from(error.into())))?;
+                let index = self.shard_index(&load_key);
+                let _ = self
+                    .run(index, |cache| match refresh {
+                        Some(refresh) => cache.set_refreshing(load_key, value.clone(), expiry, refresh).map(drop),
+                        None => cache.set(load_key, value.clone(), expiry).map(drop),
+                    })
+                    .await;
+                Ok(value)
+            })
+            .await
+            .clone()
+    }
+
+    /// [`Cache::peek`] under the owning shard's read lock.
+    pub async fn peek(&self, key: &K) -> Result<Option<V>, CacheError>
+        where
+            V: Clone,
+    {
+        self.with_shard_read(key, |cache| cache.peek(key).cloned()).await
+    }
+
+    /// [`Cache::contains`] under the owning shard's read lock.
+    pub async fn contains(&self, key: &K) -> Result<bool, CacheError> {
+        self.with_shard_read(key, |cache| cache.contains(key)).await
+    }
+
+    /// [`Cache::set`] on the owning shard.
+    pub async fn set(&self, key: K, value: V, expiry: Expiry) -> Result<Option<V>, CacheError> {
+        let index = self.shard_index(&key);
+        self.run(index, |cache| cache.set(key, value, expiry)).await?
+    }
+
+    /// [`Cache::set_if`] on the owning shard.
+    pub async fn set_if(
+        &self,
+        key: K,
+        value: V,
+        expiry: Expiry,
+        condition: SetCondition,
+    ) -> Result<SetOutcome<V>, CacheError> {
+        let index = self.shard_index(&key);
+        self.run(index, |cache| cache.set_if(key, value, expiry, condition)).await?
+    }
+
+    /// [`Cache::incr_by`] on the owning shard.
+    pub async fn incr_by(&self, key: K, delta: i64, expiry: Expiry) -> Result<i64, CacheError>
+        where
+            V: Counter,
+    {
+        let index = self.shard_index(&key);
+        self.run(index, |cache| cache.incr_by(key, delta, expiry)).await?
+    }
+
+    /// [`Cache::decr_by`] on the owning shard.
+    pub async fn decr_by(&self, key: K, delta: i64, expiry: Expiry) -> Result<i64, CacheError>
+        where
+            V: Counter,
+    {
+        let index = self.shard_index(&key);
+        self.run(index, |cache| cache.decr_by(key, delta, expiry)).await?
+    }
+
+    /// [`Cache::ttl`] under the owning shard's read lock.
+    pub async fn ttl(&self, key: &K) -> Result<Option<Option<Duration>>, CacheError> {
+        self.with_shard_read(key, |cache| cache.ttl(key)).await
+    }
+
+    /// [`Cache::expire`] on the owning shard.
+    pub async fn expire(&self, key: &K, expiry: Expiry) -> Result<bool, CacheError> {
+        self.with_shard(key, |cache| cache.expire(key, expiry)).await
+    }
+
+    /// [`Cache::persist`] on the owning shard.
+    pub async fn persist(&self, key: &K) -> Result<bool, CacheError> {
+        self.with_shard(key, |cache| cache.persist(key)).await
+    }
+
+    /// [`Cache::touch`] on the owning shard.
+    pub async fn touch(&self, key: &K, expiry: Option<Expiry>) -> Result<bool, CacheError> {
+        self.with_shard(key, |cache| cache.touch(key, expiry)).await
+    }
+
+    /// [`Cache::delete`] on the owning shard.
+    pub async fn delete(&self, key: &K) -> Result<Option<V>, CacheError> {
+        self.with_shard(key, |cache| cache.delete(key)).await
+    }
+
+    /// [`Cache::get`] for each key. Like the other batch operations, this
+    /// locks each shard once for all of its keys and returns one result per
+    /// input, in input order.
+    pub async fn get_many(&self, keys: &[K]) -> Vec<Result<Option<V>, CacheError>>
+        where
+            V: Clone,
+    {
+        let mut results: Vec<_> = (0..keys.len()).map(|_| Ok(None)).collect();
+        for (index, positions) in self.group(keys.iter()) {
+            let batch: Vec<K> = positions.iter().map(|&i| keys[i].clone()).collect();
+            let found = self.run(index, |cache| cache.get_many(&batch)).await;
+            scatter(&mut results, positions, found.map(|found| found.into_iter().map(Ok).collect()));
+        }
+        results
+    }
+
+    /// [`Cache::set`] for each item, one lock per shard.
+    pub async fn set_many(&self, items: Vec<(K, V, Expiry)>) -> Vec<Result<Option<V>, CacheError>> {
+        let mut results: Vec<_> = (0..items.len()).map(|_| Ok(None)).collect();
+        let groups = self.group(items.iter().map(|(key, _, _)| key));
+        let mut items: Vec<_> = items.into_iter().map(Some).collect();
+        for (index, positions) in groups {
+            let batch = positions.iter().filter_map(|&i| items[i].take()).collect();
+            let written = self.run(index, |cache| cache.set_many(batch)).await;
+            scatter(&mut results, positions, written);
+        }
+        results
+    }
+
+    /// [`Cache::delete`] for each key, one lock per shard.
+    pub async fn delete_many(&self, keys: &[K]) -> Vec<Result<Option<V>, CacheError>> {
+        let mut results: Vec<_> = (0..keys.len()).map(|_| Ok(None)).collect();
+        for (index, positions) in self.group(keys.iter()) {
+            let batch: Vec<K> = positions.iter().map(|&i| keys[i].clone()).collect();
+            let removed = self.run(index, |cache| cache.delete_many(&batch)).await;
+            scatter(&mut results, positions, removed.map(|removed| removed.into_iter().map(Ok).collect()));
+        }
+        results
+    }
+
+    // Positions of `keys`, grouped by the shard that owns them.
+    fn group<'a>(&self, keys: impl Iterator<Item = &'a K>) -> BTreeMap<usize, Vec<usize>>
+        where
+            K: 'a,
+    {
+        let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (position, key) in keys.enumerate() {
+            groups.entry(self.shard_index(key)).or_default().push(position);
+        }
+        groups
+    }
+
+    /// Reclaims up to `limit` expired entries from each shard, locking one
+    /// shard at a time, and returns how many were reclaimed.
+    pub async fn purge_expired(&self, limit: usize) -> usize {
+        let mut purged = 0;
+        for index in 0..self.shards.len() {
+            purged += self.run(index, |cache| cache.purge_expired(limit)).await.unwrap_or(0);
+        }
+        purged
+    }
+
+    /// The number of stored entries across all shards.
+    pub async fn len(&self) -> usize {
+        let mut len = 0;
+        for shard in &self.shards {
+            len += shard.read().await.len();
+        }
+        len
+    }
+
+    /// Whether no shard holds any entries.
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// The statistics of all shards combined.
+    pub async fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for shard in &self.shards {
+            stats += shard.read().await.stats();
+        }
+        stats
+    }
+
+    /// Resets every shard's statistics and returns their combined values
+    /// from just before. Shards are reset one at a time, so operations on
+    /// other shards may land in either total.
+    pub async fn reset_stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for shard in &self.shards {
+            stats += shard.write().await.reset_stats();
+        }
+        stats
+    }
+
+    /// The total weight across all shards.
+    pub async fn weight(&self) -> usize {
+        let mut weight = 0;
+        for shard in &self.shards {
+            weight += shard.read().await.weight();
+        }
+        weight
+    }
+}
+
+fn adjust(total: &AtomicUsize, before: usize, after: usize) {
+    if after > before {
+        total.fetch_add(after - before, Ordering::Relaxed);
+    } else {
+        total.fetch_sub(before - after, Ordering::Relaxed);
+    }
+}
+
+// Writes a shard's batch results back to their input positions; if the whole
+// shard operation failed, every key in it gets that error.
+fn scatter<T>(
+    results: &mut [Result<T, CacheError>],
+    positions: Vec<usize>,
+    batch: Result<Vec<Result<T, CacheError>>, CacheError>,
+) {
+    match batch {
+        Ok(batch) => {
+            for (position, result) in positions.into_iter().zip(batch) {
+                results[position] = result;
+            }
+        }
+        Err(error) => {
+            for position in positions {
+                results[position] = Err(error.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn sharded(shards: usize, capacity: usize) -> ShardedCache<String, String> {
+        ShardedCache::new(shards, capacity, Cache::new)
+    }
+
+    // A cache whose time only moves when the returned clock is advanced.
+    fn clocked(shards: usize) -> (Arc<ShardedCache<String, String>>, Arc<ManualClock>) {
+        let clock = ManualClock::new();
+        let shard_clock = Arc::clone(&clock);
+        let cache = ShardedCache::new(shards, 100, move |capacity| {
+            Cache::new(capacity).with_clock(shard_clock.clone())
+        });
+        (Arc::new(cache), clock)
+    }
+
+    // Yields to the spawned tasks until `done` holds, failing the test if
+    // that takes more than a few seconds.
+    async fn eventually(mut done: impl FnMut() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !done() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("timed out waiting for the spawned tasks");
+    }
+
+    // How many loads share the flight for `key`, counting the map's handle.
+    fn flight_holders(cache: &ShardedCache<String, String>, key: &str) -> usize {
+        cache
+            .inflight
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(0, Arc::strong_count)
+    }
+
+    // Starts `tasks` concurrent `get_with` calls for `key`, each with a
+    // loader that counts its calls in `loads`, waits a little and returns
+    // `result`.
+    fn spawn_loads(
+        cache: &Arc<ShardedCache<String, String>>,
+        key: &str,
+        tasks: usize,
+        loads: &Arc<AtomicUsize>,
+        result: Result<&str, &str>,
+    ) -> Vec<tokio::task::JoinHandle<Result<String, CacheError>>> {
+        let result = result.map(str::to_string).map_err(str::to_string);
+        (0..tasks)
+            .map(|_| {
+                let cache = Arc::clone(cache);
+                let (key, loads, result) = (key.to_string(), Arc::clone(loads), result.clone());
+                tokio::spawn(async move {
+                    cache
+                        .get_with(key, Expiry::Never, |_| async move {
+                            loads.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(20)).await;
+                            result
+                        })
+                        .await
+                })
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn capacity_is_shared_by_all_shards() {
+        let cache = sharded(4, 4);
+        for i in 0..4 {
+            cache.set(i.to_string(), "v".to_string(), Expiry::Never).await.unwrap();
+        }
+        let stats = cache.stats().await;
+        assert_eq!((stats.entries, stats.evictions), (4, 0));
+
+        for i in 4..20 {
+            cache.set(i.to_string(), "v".to_string(), Expiry::Never).await.unwrap();
+            assert_eq!(cache.len().await, 4);
+        }
+        assert!(cache.contains(&"19".to_string()).await.unwrap());
+        assert_eq!(cache.stats().await.evictions, 16);
+    }
+
+    #[tokio::test]
+    async fn weight_budget_is_shared_by_all_shards() {
+        let cache = ShardedCache::new(4, 100, |capacity| Cache::new(capacity).with_max_weight(400));
+        let value = "v".repeat(200);
+
+        cache.set("a".to_string(), value.clone(), Expiry::Never).await.unwrap();
+        assert!(matches!(
+            cache.set("b".to_string(), "v".repeat(400), Expiry::Never).await,
+            Err(CacheError::TooLarge { weight: 401, max_weight: 400 })
+        ));
+        cache.set("b".to_string(), value.clone(), Expiry::Never).await.unwrap();
+        assert_eq!(cache.weight().await, 201);
+        assert!(cache.contains(&"b".to_string()).await.unwrap());
+        assert_eq!(cache.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_share_one_load() {
+        let cache = Arc::new(sharded(4, 100));
+        let loads = Arc::new(AtomicUsize::new(0));
+        for handle in spawn_loads(&cache, "k", 20, &loads, Ok("loaded")) {
+            assert_eq!(handle.await.unwrap().unwrap(), "loaded");
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.peek(&"k".to_string()).await.unwrap().as_deref(), Some("loaded"));
+        assert!(cache.inflight.lock().unwrap().is_empty());
+
+        // Hits do not load at all.
+        for handle in spawn_loads(&cache, "k", 5, &loads, Ok("reloaded")) {
+            assert_eq!(handle.await.unwrap().unwrap(), "loaded");
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_loads_reach_every_waiter_and_are_not_cached() {
+        let cache = Arc::new(sharded(4, 100));
+        let loads = Arc::new(AtomicUsize::new(0));
+        for handle in spawn_loads(&cache, "k", 10, &loads, Err("upstream down")) {
+            match handle.await.unwrap() {
+                Err(CacheError::Load(error)) => assert_eq!(error.to_string(), "upstream down"),
+                other => panic!("expected a load error, got {:?}", other),
+            }
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(!cache.contains(&"k".to_string()).await.unwrap());
+
+        for handle in spawn_loads(&cache, "k", 1, &loads, Ok("loaded")) {
+            assert_eq!(handle.await.unwrap().unwrap(), "loaded");
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn waiters_finish_when_the_leader_is_cancelled() {
+        let cache = Arc::new(sharded(4, 100));
+        let leader = {
+            let cache = Arc::clone(&cache);
+            tokio::spawn(async move {
+                cache
+                    .get_with("k".to_string(), Expiry::Never, |_| {
+                        std::future::pending::<Result<String, String>>()
+                    })
+                    .await
+            })
+        };
+        eventually(|| flight_holders(&cache, "k") == 2).await;
+        let loads = Arc::new(AtomicUsize::new(0));
+        let waiters = spawn_loads(&cache, "k", 3, &loads, Ok("loaded"));
+        eventually(|| flight_holders(&cache, "k") == 5).await;
+        assert_eq!(loads.load(Ordering::SeqCst), 0);
+
+        leader.abort();
+        assert!(leader.await.unwrap_err().is_cancelled());
+        for waiter in waiters {
+            assert_eq!(waiter.await.unwrap().unwrap(), "loaded");
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(cache.inflight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_load_without_waiters_is_forgotten() {
+        let (cache, clock) = clocked(4);
+        let leader = {
+            let cache = Arc::clone(&cache);
+            tokio::spawn(async move {
+                cache
+                    .get_with("k".to_string(), Expiry::Never, |_| {
+                        std::future::pending::<Result<String, String>>()
+                    })
+                    .await
+            })
+        };
+        eventually(|| flight_holders(&cache, "k") == 2).await;
+
+        leader.abort();
+        assert!(leader.await.unwrap_err().is_cancelled());
+        assert!(cache.inflight.lock().unwrap().is_empty());
+
+        // Background refreshes of the key are not skipped either.
+        let refresh = Refresh {
+            ahead: Some(0.5),
+            ..Default::default()
+        };
+        assert_eq!(read(&cache, refresh, Ok("v1")).await.unwrap(), "v1");
+        clock.advance_ms(60);
+        assert_eq!(read(&cache, refresh, Ok("v2")).await.unwrap(), "v1");
+        refreshed_to(&cache, "v2").await;
+    }
+
+    // A `get_with_refresh` of a key that lives 100ms, loading `result`.
+    async fn read(
+        cache: &Arc<ShardedCache<String, String>>,
+        refresh: Refresh,
+        result: Result<&str, &str>,
+    ) -> Result<String, CacheError> {
+        let result = result.map(str::to_string).map_err(str::to_string);
+        let expiry = Expiry::After(Duration::from_millis(100));
+        cache
+            .get_with_refresh("k".to_string(), expiry, refresh, |_| async move { result })
+            .await
+    }
+
+    async fn peek(cache: &ShardedCache<String, String>) -> Option<String> {
+        cache.peek(&"k".to_string()).await.unwrap()
+    }
+
+    // Waits for a background reload to store `value`.
+    async fn refreshed_to(cache: &ShardedCache<String, String>, value: &str) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while peek(cache).await.as_deref() != Some(value) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("timed out waiting for the background reload");
+    }
+
+    #[tokio::test]
+    async fn refresh_ahead_reloads_in_the_background() {
+        let (cache, clock) = clocked(1);
+        let refresh = Refresh {
+            ahead: Some(0.5),
+            ..Default::default()
+        };
+        assert_eq!(read(&cache, refresh, Ok("v1")).await.unwrap(), "v1");
+        clock.advance_ms(40);
+        assert_eq!(read(&cache, refresh, Ok("v2")).await.unwrap(), "v1");
+        tokio::task::yield_now().await;
+        assert_eq!(peek(&cache).await.as_deref(), Some("v1"));
+
+        clock.advance_ms(20);
+        assert_eq!(read(&cache, refresh, Ok("v2")).await.unwrap(), "v1");
+        refreshed_to(&cache, "v2").await;
+    }
+
+    #[tokio::test]
+    async fn stale_while_revalidate_serves_the_old_value() {
+        let (cache, clock) = clocked(1);
+        let refresh = Refresh {
+            stale_while_revalidate: Duration::from_millis(100),
+            ..Default::default()
+        };
+        read(&cache, refresh, Ok("v1")).await.unwrap();
+        clock.advance_ms(120);
+        assert_eq!(peek(&cache).await, None);
+        assert_eq!(read(&cache, refresh, Ok("v2")).await.unwrap(), "v1");
+        refreshed_to(&cache, "v2").await;
+
+        // Past the window the reload happens in the foreground.
+        clock.advance_ms(220);
+        assert_eq!(read(&cache, refresh, Ok("v3")).await.unwrap(), "v3");
+    }
+
+    #[tokio::test]
+    async fn stale_if_error_covers_failed_reloads() {
+        let (cache, clock) = clocked(1);
+        let refresh = Refresh {
+            stale_if_error: Duration::from_millis(100),
+            ..Default::default()
+        };
+        read(&cache, refresh, Ok("v1")).await.unwrap();
+        clock.advance_ms(120);
+        assert_eq!(read(&cache, refresh, Err("down")).await.unwrap(), "v1");
+        assert_eq!(read(&cache, refresh, Ok("v2")).await.unwrap(), "v2");
+
+        clock.advance_ms(220);
+        assert!(matches!(read(&cache, refresh, Err("down")).await, Err(CacheError::Load(_))));
+        assert_eq!(cache.len().await, 0);
+    }
+}
@@ -0,0 +1,55 @@
+use std::ops::AddAssign;
+
+/// Counters kept by a [`Cache`](crate::Cache) since it was created or its
+/// statistics were last reset, plus its current size.
+///
+/// Only [`get`](crate::Cache::get) and the operations built on it count as
+/// reads; [`peek`](crate::Cache::peek), [`contains`](crate::Cache::contains)
+/// and [`ttl`](crate::Cache::ttl) leave the counters alone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads that found a live entry.
+    pub hits: u64,
+    /// Reads that found no live entry.
+    pub misses: u64,
+    /// Misses that found an expired entry, also counted in `misses`.
+    pub expired_on_read: u64,
+    /// Entries dropped by the eviction policy to make room.
+    pub evictions: u64,
+    /// Writes that created an entry.
+    pub inserts: u64,
+    /// Writes that replaced a live entry.
+    pub overwrites: u64,
+    /// Deletes that removed an entry.
+    pub deletes: u64,
+    /// Entries currently stored, including expired ones not yet reclaimed.
+    pub entries: usize,
+    /// Current total weight of the stored entries.
+    pub weight: usize,
+}
+
+impl CacheStats {
+    /// The fraction of reads that were hits, or zero if there were none.
+    pub fn hit_ratio(&self) -> f64 {
+        let reads = self.hits + self.misses;
+        if reads == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / reads as f64
+    }
+}
+
+// Sums the statistics of several shards.
+impl AddAssign for CacheStats {
+    fn add_assign(&mut self, other: CacheStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.expired_on_read += other.expired_on_read;
+        self.evictions += other.evictions;
+        self.inserts += other.inserts;
+        self.overwrites += other.overwrites;
+        self.deletes += other.deletes;
+        self.entries += other.entries;
+        self.weight += other.weight;
+    }
+}
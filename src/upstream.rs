@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use hyper::{Client, StatusCode, Uri};
+use raw_cache::{Expiry, Refresh};
+
+use crate::server::{StoredValue, MAX_BODY_BYTES};
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Read-through source for keys the cache does not hold: a `GET` that misses
+// fetches `{base}/{key}` and caches a 200 response, body and representation
+// headers included. `refresh` keeps popular keys from expiring under load.
+#[derive(Clone)]
+pub struct Upstream {
+    client: Client<HttpConnector>,
+    base: String,
+    expiry: Expiry,
+    refresh: Refresh,
+}
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    NotFound,
+    Status(StatusCode),
+    TooLarge,
+    Timeout,
+    Request(hyper::Error),
+    InvalidUri(String),
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::NotFound => write!(f, "upstream has no such key"),
+            UpstreamError::Status(status) => write!(f, "upstream responded with {}", status),
+            UpstreamError::TooLarge => write!(f, "upstream value exceeds {} bytes", MAX_BODY_BYTES),
+            UpstreamError::Timeout => write!(f, "upstream did not respond within {:?}", UPSTREAM_TIMEOUT),
+            UpstreamError::Request(error) => write!(f, "upstream request failed: {}", error),
+            UpstreamError::InvalidUri(uri) => write!(f, "invalid upstream URI `{}`", uri),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+impl Upstream {
+    // Loaded values are cached with `expiry`.
+    pub fn new(base: &Uri, expiry: Expiry, refresh: Refresh) -> Self {
+        Upstream {
+            client: Client::new(),
+            base: base.to_string().trim_end_matches('/').to_string(),
+            expiry,
+            refresh,
+        }
+    }
+
+    pub fn expiry(&self) -> Expiry {
+        self.expiry
+    }
+
+    pub fn refresh(&self) -> Refresh {
+        self.refresh
+    }
+
+    pub async fn fetch(&self, key: String) -> Result<StoredValue, UpstreamError> {
+        tokio::time::timeout(UPSTREAM_TIMEOUT, self.request(&key))
+            .await
+            .map_err(|_| UpstreamError::Timeout)?
+    }
+
+    async fn request(&self, key: &str) -> Result<StoredValue, UpstreamError> {
+        let uri = format!("{}/{}", self.base, encode_key(key));
+        let uri: Uri = uri.parse().map_err(|_| UpstreamError::InvalidUri(uri))?;
+        let response = self.client.get(uri).await.map_err(UpstreamError::Request)?;
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_FOUND => return Err(UpstreamError::NotFound),
+            status => return Err(UpstreamError::Status(status)),
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value: &hyper::header::HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let content_type = header(CONTENT_TYPE);
+        let content_encoding = header(CONTENT_ENCODING);
+
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(UpstreamError::Request)?;
+            if (bytes.len() + chunk.len()) as u64 > MAX_BODY_BYTES {
+                return Err(UpstreamError::TooLarge);
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(StoredValue {
+            body: bytes.into(),
+            content_type,
+            content_encoding,
+        })
+    }
+}
+
+// Percent-encodes everything that cannot appear in a path segment, and `%`
+// itself, so the upstream sees the same key the client asked for.
+fn encode_key(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
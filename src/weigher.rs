@@ -0,0 +1,38 @@
+/// Measures how much of a cache's weight budget an entry uses.
+///
+/// Any `Fn(&K, &V) -> usize` closure is a weigher.
+pub trait Weigher<K, V> {
+    /// The weight of `value` stored under `key`.
+    fn weigh(&self, key: &K, value: &V) -> usize;
+}
+
+impl<K, V, F> Weigher<K, V> for F
+    where
+        F: Fn(&K, &V) -> usize,
+{
+    fn weigh(&self, key: &K, value: &V) -> usize {
+        self(key, value)
+    }
+}
+
+/// Every entry weighs one, so the weight is just the entry count.
+pub struct EntryCount;
+
+impl<K, V> Weigher<K, V> for EntryCount {
+    fn weigh(&self, _key: &K, _value: &V) -> usize {
+        1
+    }
+}
+
+/// Weighs an entry as the byte length of its key plus its value.
+pub struct ByteLen;
+
+impl<K, V> Weigher<K, V> for ByteLen
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+{
+    fn weigh(&self, key: &K, value: &V) -> usize {
+        key.as_ref().len() + value.as_ref().len()
+    }
+}